@@ -0,0 +1,363 @@
+//! Expands the `files` mappings of the config into single files
+
+use capsules_lib::{Error, FileTarget, SetError, check_symlink, safe_relative_path};
+use globset::{GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// gitignore style patterns next to the config, applied to directories and globs
+const IGNORE_FILE: &str = ".capsuleignore";
+
+/// Files left out when expanding directories and glob patterns
+pub struct Filter {
+    base: PathBuf,
+    exclude: GlobSet,
+    ignore: Gitignore,
+}
+
+impl Filter {
+    /// `exclude` patterns are relative to `base`, the config directory
+    pub fn new(base: &Path, exclude: &[String]) -> Result<Self, Error> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in exclude {
+            builder.add(glob(pattern)?.glob().clone());
+        }
+        let exclude = builder.build().set_error(Error::InternalError)?;
+
+        let ignore_file = base.join(IGNORE_FILE);
+        let ignore = if ignore_file.is_file() {
+            let mut builder = GitignoreBuilder::new(base);
+            if builder.add(&ignore_file).is_some() {
+                return Err(Error::CouldNotReadFile(ignore_file.display().to_string()));
+            }
+            builder
+                .build()
+                .set_error(Error::CouldNotReadFile(ignore_file.display().to_string()))?
+        } else {
+            Gitignore::empty()
+        };
+
+        Ok(Filter {
+            base: base.to_path_buf(),
+            exclude,
+            ignore,
+        })
+    }
+
+    fn excluded(&self, path: &Path, is_dir: bool) -> bool {
+        // sources outside of the config directory only go through `exclude`
+        let Ok(relative) = path.strip_prefix(&self.base) else {
+            return self.exclude.is_match(path);
+        };
+        self.exclude.is_match(relative)
+            || self
+                .ignore
+                .matched_path_or_any_parents(path, is_dir)
+                .is_ignore()
+    }
+}
+
+fn glob(pattern: &str) -> Result<GlobMatcher, Error> {
+    GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map(|g| g.compile_matcher())
+        .set_error(Error::InvalidPattern(pattern.to_string()))
+}
+
+fn is_glob(source: &str) -> bool {
+    source.contains(['*', '?', '[', '{'])
+}
+
+/// Path with forward slashes so capsules extract the same everywhere
+fn slashes(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn join_target(target: &str, relative: &Path) -> String {
+    let relative = slashes(relative);
+    let target = target.trim_end_matches(['/', '\\']);
+    if target.is_empty() {
+        relative
+    } else {
+        format!("{}/{}", target, relative)
+    }
+}
+
+/// Target of `path` when it is a symlink worth keeping as is. Links with an
+/// absolute target would dangle on another machine, those are followed
+fn relative_link(path: &Path) -> Option<PathBuf> {
+    if !path.symlink_metadata().ok()?.is_symlink() {
+        return None;
+    }
+    fs::read_link(path).ok().filter(|link| link.is_relative())
+}
+
+/// Files and kept symlinks under `root` that are not filtered out, relative to `root`
+fn walk(root: &Path, filter: &Filter) -> Result<Vec<(PathBuf, Option<PathBuf>)>, Error> {
+    let mut files = vec![];
+    let mut entries = WalkDir::new(root)
+        .follow_links(true)
+        .into_iter()
+        .filter_entry(|e| e.depth() == 0 || !filter.excluded(e.path(), e.file_type().is_dir()));
+    while let Some(entry) = entries.next() {
+        let entry = entry.set_error(Error::CouldNotReadFile(root.display().to_string()))?;
+        let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+        let link = match entry.depth() {
+            0 => None,
+            _ => relative_link(entry.path()),
+        };
+        if link.is_some() {
+            if entry.file_type().is_dir() {
+                entries.skip_current_dir();
+            }
+            files.push((relative.to_path_buf(), link));
+        } else if entry.file_type().is_file() {
+            files.push((relative.to_path_buf(), None));
+        }
+    }
+    Ok(files)
+}
+
+/// A single file or symlink to embed
+pub struct Entry {
+    pub source: PathBuf,
+    pub target: String,
+    /// Permissions set in the config
+    pub mode: Option<u32>,
+    /// Target of a symlink kept as is, with forward slashes
+    pub symlink: Option<String>,
+}
+
+/// Resolves `source -> target` mappings into single files, sorted by target.
+/// Targets are checked to stay inside the directory they are extracted to.
+/// A source is a file, a directory taken recursively or a glob pattern like
+/// `dist/**/*.js`, relative to `base`. Directories and globs keep their
+/// structure under the target
+pub fn expand(
+    files: &HashMap<String, FileTarget>,
+    base: &Path,
+    filter: &Filter,
+) -> Result<Vec<Entry>, Error> {
+    let mut resolved = BTreeMap::new();
+    let mut add = |source: PathBuf, target: String, mode, link: Option<PathBuf>| {
+        let target = safe_relative_path(&target)?;
+        let symlink = link.as_deref().map(slashes);
+        if let Some(link) = &symlink {
+            check_symlink(&target, link)?;
+        }
+        let entry = Entry {
+            source,
+            target: target.clone(),
+            mode,
+            symlink,
+        };
+        if resolved.insert(target.clone(), entry).is_some() {
+            return Err(Error::DuplicateTarget(target));
+        }
+        Ok(())
+    };
+
+    for (source, file_target) in files {
+        let (target, mode) = (file_target.path(), file_target.mode());
+        if is_glob(source) {
+            // walk from the last directory before the first wildcard
+            let mut root = PathBuf::new();
+            let mut pattern = vec![];
+            for component in Path::new(source).components() {
+                let part = component.as_os_str().to_string_lossy();
+                if pattern.is_empty() && !is_glob(&part) {
+                    root.push(component);
+                } else {
+                    pattern.push(part.to_string());
+                }
+            }
+            let matcher = glob(&pattern.join("/"))?;
+            let root = base.join(root);
+            let mut matched = false;
+            for (relative, link) in walk(&root, filter)? {
+                if matcher.is_match(&relative) {
+                    matched = true;
+                    add(
+                        root.join(&relative),
+                        join_target(target, &relative),
+                        mode,
+                        link,
+                    )?;
+                }
+            }
+            if !matched {
+                return Err(Error::NoFilesMatched(source.clone()));
+            }
+            continue;
+        }
+
+        let path = base.join(source);
+        if let Some(link) = relative_link(&path) {
+            add(path, target.to_string(), mode, Some(link))?;
+        } else if path.is_dir() {
+            for (relative, link) in walk(&path, filter)? {
+                add(
+                    path.join(&relative),
+                    join_target(target, &relative),
+                    mode,
+                    link,
+                )?;
+            }
+        } else {
+            add(path, target.to_string(), mode, None)?;
+        }
+    }
+    // the runtime refuses to extract through a link, a link may point anywhere
+    // once another one moved it
+    for target in resolved.keys() {
+        let mut parent = target.as_str();
+        while let Some((up, _)) = parent.rsplit_once('/') {
+            if resolved.get(up).is_some_and(|e| e.symlink.is_some()) {
+                return Err(Error::UnsafePath(target.clone()));
+            }
+            parent = up;
+        }
+    }
+    Ok(resolved.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Entry, Filter, IGNORE_FILE, expand};
+    use capsules_lib::scratch::Scratch;
+    use capsules_lib::{Error, FileTarget};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+
+    /// Config directory with an app, its tests and its dependencies
+    fn project(name: &str) -> Scratch {
+        let base = Scratch::new(&format!("files-{}", name));
+        for file in [
+            "app/main.js",
+            "app/lib/util.js",
+            "app/lib/util.test.js",
+            "app/node_modules/dep/index.js",
+        ] {
+            base.write(file, file);
+        }
+        base.write(IGNORE_FILE, "# dependencies\nnode_modules/\n");
+        base
+    }
+
+    fn mappings(json: &str) -> HashMap<String, FileTarget> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn targets(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|e| e.target.as_str()).collect()
+    }
+
+    fn expand_in(base: &Path, json: &str, exclude: &[&str]) -> Result<Vec<Entry>, Error> {
+        let exclude: Vec<String> = exclude.iter().map(|e| e.to_string()).collect();
+        expand(&mappings(json), base, &Filter::new(base, &exclude)?)
+    }
+
+    #[test]
+    fn directories_keep_their_structure() {
+        let base = project("dir");
+        let entries =
+            expand_in(&base, r#"{"app": "srv/", "app/main.js": "main.js"}"#, &[]).unwrap();
+        // sorted by target, node_modules is in .capsuleignore
+        assert_eq!(
+            targets(&entries),
+            [
+                "main.js",
+                "srv/lib/util.js",
+                "srv/lib/util.test.js",
+                "srv/main.js"
+            ]
+        );
+        assert_eq!(entries[1].source, base.join("app").join("lib/util.js"));
+    }
+
+    #[test]
+    fn globs_are_walked_from_their_last_plain_directory() {
+        let base = project("glob");
+        let entries = expand_in(&base, r#"{"app/lib/*.js": "lib"}"#, &[]).unwrap();
+        assert_eq!(targets(&entries), ["lib/util.js", "lib/util.test.js"]);
+        assert_eq!(entries[0].source, base.join("app/lib").join("util.js"));
+
+        // `*` does not cross directories, `**` does
+        let entries = expand_in(&base, r#"{"app/*.js": "."}"#, &[]).unwrap();
+        assert_eq!(targets(&entries), ["main.js"]);
+        let entries = expand_in(&base, r#"{"app/**/util.*": "js"}"#, &[]).unwrap();
+        assert_eq!(targets(&entries), ["js/lib/util.js", "js/lib/util.test.js"]);
+    }
+
+    #[test]
+    fn exclude_and_capsuleignore_filter_files() {
+        let base = project("exclude");
+        let entries = expand_in(&base, r#"{"app": "app"}"#, &["**/*.test.js"]).unwrap();
+        assert_eq!(targets(&entries), ["app/lib/util.js", "app/main.js"]);
+
+        fs::remove_file(base.join(IGNORE_FILE)).unwrap();
+        let entries = expand_in(&base, r#"{"app/**/*.js": "app"}"#, &["app/lib"]).unwrap();
+        assert_eq!(
+            targets(&entries),
+            ["app/main.js", "app/node_modules/dep/index.js"]
+        );
+    }
+
+    #[test]
+    fn mappings_are_checked() {
+        let base = project("errors");
+        assert!(matches!(
+            expand_in(&base, r#"{"app/main.js": "x.js", "app/lib/util.js": "x.js"}"#, &[]),
+            Err(Error::DuplicateTarget(target)) if target == "x.js"
+        ));
+        assert!(matches!(
+            expand_in(&base, r#"{"app/**/*.py": "py"}"#, &[]),
+            Err(Error::NoFilesMatched(source)) if source == "app/**/*.py"
+        ));
+        assert!(matches!(
+            expand_in(&base, r#"{"app/main.js": "../main.js"}"#, &[]),
+            Err(Error::UnsafePath(_))
+        ));
+        assert!(matches!(
+            expand_in(&base, r#"{"app/[a.js": "a.js"}"#, &[]),
+            Err(Error::InvalidPattern(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn targets_can_not_go_through_symlinks() {
+        let base = project("chained");
+        std::os::unix::fs::symlink(".", base.join("t")).unwrap();
+        std::os::unix::fs::symlink("../outside", base.join("esc")).unwrap();
+        let mappings = r#"{"t": "t", "esc": "t/esc", "app/main.js": "t/esc/pwned"}"#;
+        assert!(matches!(
+            expand_in(&base, mappings, &[]),
+            Err(Error::UnsafePath(target)) if target == "t/esc"
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn relative_symlinks_are_kept() {
+        let base = project("links");
+        std::os::unix::fs::symlink("main.js", base.join("app/index.js")).unwrap();
+        std::os::unix::fs::symlink("lib", base.join("app/current")).unwrap();
+        let entries = expand_in(&base, r#"{"app": "app"}"#, &["**/node_modules"]).unwrap();
+        let links: Vec<_> = entries
+            .iter()
+            .filter_map(|e| Some((e.target.as_str(), e.symlink.as_deref()?)))
+            .collect();
+        // the linked directory is not walked
+        assert_eq!(links, [("app/current", "lib"), ("app/index.js", "main.js")]);
+        assert_eq!(entries.len(), 5);
+    }
+}
@@ -1,22 +1,30 @@
+mod files;
+
 mod runtime_binaries {
     include!(concat!(env!("OUT_DIR"), "/runtime_binaries.rs"));
 }
 
+use capsules_lib::env::check_env;
+use capsules_lib::info::CapsuleInfo;
+use capsules_lib::keys::{self, KEY_SIZE, Kdf};
+use capsules_lib::payload::{Lock, Payload, PayloadWriter, hex};
+use capsules_lib::secrets::{SealedSecrets, Secret, SecretsKey};
+use capsules_lib::signing::{self, Verification};
 use capsules_lib::{
-    ASCII_ART, Capsule, Error, MAGIC_NUMBER_ENCRYPTED, MAGIC_NUMBER_PLAIN, RUNTIME_TARGETS,
-    SetError, encrypt,
+    ASCII_ART, BuildInfo, Capsule, EmbeddedFile, Error, RUNTIME_TARGETS, SetError,
+    check_instance_name, check_process_name, safe_relative_path, start_order,
 };
-use clap::{Parser, builder::PossibleValuesParser};
+use clap::{Parser, Subcommand, ValueEnum, builder::PossibleValuesParser};
+use files::{Entry, Filter, expand};
+use rpassword::prompt_password;
 use runtime_binaries::RUNTIME_BINARIES;
 use std::{
     collections::HashMap,
     env,
     fs::{self, File},
-    io::Write,
+    io::{BufWriter, Write},
     path::{Path, PathBuf},
 };
-use uuid::Uuid;
-use zip::{ZipWriter, write::SimpleFileOptions};
 
 fn target_parser() -> PossibleValuesParser {
     PossibleValuesParser::new(RUNTIME_BINARIES.iter().map(|(k, _)| *k))
@@ -24,42 +32,173 @@ fn target_parser() -> PossibleValuesParser {
 
 /// The Capsules compiler
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None, before_help=ASCII_ART)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    before_help = ASCII_ART,
+    subcommand_negates_reqs = true,
+    args_conflicts_with_subcommands = true
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Cmd>,
+
     /// The capsule input file
-    #[arg(short, long)]
-    input_file: PathBuf,
+    #[arg(short, long, required = true)]
+    input_file: Option<PathBuf>,
 
     /// Output target
-    #[arg(short, long, value_parser=target_parser())]
-    target: String,
+    #[arg(short, long, value_parser=target_parser(), required = true)]
+    target: Option<String>,
 
     /// Encryption password
-    #[arg(short, long)]
+    #[arg(short, long, group = "lock")]
     password: Option<String>,
 
+    /// Encrypt with a 32 byte key instead of a password, in hex or a file
+    /// holding it. Skips the key derivation when the capsule starts
+    #[arg(long, group = "lock", value_name = "KEY")]
+    key: Option<String>,
+
+    /// Encrypt for the X25519 public key of the host running the capsule, in hex
+    /// or a file holding it. The host unlocks it with its private key
+    #[arg(long, group = "lock", value_name = "PUBLIC_KEY")]
+    recipient: Option<String>,
+
+    /// Key derivation of the passwords
+    #[arg(long, value_enum, default_value_t = KdfArg::Pbkdf2)]
+    kdf: KdfArg,
+
+    /// Password of the secrets, they are sealed with the payload key otherwise
+    #[arg(long)]
+    secrets_password: Option<String>,
+
+    /// Sign the capsule with an Ed25519 key, in hex or a file holding it
+    #[arg(long, value_name = "KEY")]
+    sign_key: Option<String>,
+
     /// Output executable
     #[arg(short, long)]
     output_path: Option<PathBuf>,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum KdfArg {
+    /// PBKDF2-SHA256, 600k iterations
+    Pbkdf2,
+    /// Argon2id, 19 MiB and 2 passes
+    Argon2id,
+}
+
+#[derive(Subcommand, Debug)]
+enum Cmd {
+    /// Print the version, processes and files of a built capsule without running it
+    Inspect {
+        /// The capsule executable
+        binary: PathBuf,
+
+        /// Password or hex key of an encrypted capsule, prompted for when missing
+        #[arg(short, long)]
+        password: Option<String>,
+    },
+    /// Write a random key to a file, usable with --key or as the X25519 private
+    /// key of a host, and print its public key for --recipient
+    Keygen {
+        /// File the key is written to in hex, it must not exist
+        output: PathBuf,
+
+        /// Print the Ed25519 public key instead, the key is for --sign-key
+        /// and the public key for --trusted-keys
+        #[arg(long)]
+        sign: bool,
+    },
+    /// Check the digest of a built capsule and report who signed it
+    Verify {
+        /// The capsule executable
+        binary: PathBuf,
+
+        /// File of Ed25519 public keys trusted to sign the capsule, in hex
+        #[arg(long, value_name = "FILE")]
+        trusted_keys: Option<PathBuf>,
+    },
+}
+
 fn main() {
-    match run() {
+    let args = Args::parse();
+    let result = match args.command {
+        Some(Cmd::Inspect { binary, password }) => inspect(&binary, password),
+        Some(Cmd::Keygen { output, sign }) => keygen(&output, sign),
+        Some(Cmd::Verify {
+            binary,
+            trusted_keys,
+        }) => verify(&binary, trusted_keys.as_deref()),
+        None => run(args),
+    };
+    match result {
         Ok(()) => (),
         Err(e) => e.exit(),
     }
 }
 
-fn run() -> Result<(), Error> {
-    let args = Args::parse();
+fn inspect(binary: &Path, password: Option<String>) -> Result<(), Error> {
+    let mut payload = Payload::open(binary)?;
+    payload.verify()?;
+    if payload.is_encrypted() {
+        let password = match password {
+            Some(password) => password,
+            None => prompt_password(payload.scheme().prompt()).set_error(Error::InternalError)?,
+        };
+        payload.unlock(&password)?;
+    }
+    let capsule = payload.manifest()?;
+    print!("{}", CapsuleInfo::new(&capsule, payload.scheme()));
+    Ok(())
+}
+
+fn keygen(output: &Path, sign: bool) -> Result<(), Error> {
+    let key = keys::generate_key();
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(output)
+        .and_then(|mut file| writeln!(file, "{}", hex(&key)))
+        .set_error(Error::CouldNotWriteFile(output.display().to_string()))?;
+    let public = match sign {
+        true => signing::public_key(&key),
+        false => keys::public_key(&key),
+    };
+    println!("{}", hex(&public));
+    Ok(())
+}
+
+fn verify(binary: &Path, trusted_keys: Option<&Path>) -> Result<(), Error> {
+    let trusted = match trusted_keys {
+        Some(path) => signing::read_trusted_keys(path)?,
+        None => vec![],
+    };
+    let mut payload = Payload::open(binary)?;
+    let verification = Verification::new(&mut payload, &trusted);
+    print!("{}", verification);
+    verification.result(&trusted)
+}
+
+fn run(args: Args) -> Result<(), Error> {
+    let (Some(input_file), Some(target)) = (args.input_file, args.target) else {
+        return Err(Error::InternalError);
+    };
 
     let cwd = env::current_dir().set_error(Error::InternalError)?;
-    let input_path = if !args.input_file.is_absolute() {
-        cwd.join(args.input_file)
+    let input_path = if !input_file.is_absolute() {
+        cwd.join(input_file)
     } else {
-        args.input_file
+        input_file
     };
-    let target = args.target;
     let output_path = args
         .output_path
         .unwrap_or_else(|| default_output(&input_path, &target));
@@ -68,35 +207,77 @@ fn run() -> Result<(), Error> {
     let input_file_content = fs::read_to_string(&input_path)
         .set_error(Error::CouldNotReadFile(input_path.display().to_string()))?;
 
-    let file = deserialize(&input_file_content).ok_or(Error::InvalidDataFormat)?;
+    let mut file = deserialize(&input_file_content)?;
+    file.env.iter().try_for_each(check_env)?;
+    if let Some(processes) = &mut file.processes {
+        start_order(processes)?;
+        for (name, process) in processes.iter_mut() {
+            check_process_name(name)?;
+            process.env.iter().try_for_each(check_env)?;
+            let cwd = safe_relative_path(process.cwd.as_deref().unwrap_or(name))?;
+            if process.cwd.is_some() {
+                process.cwd = Some(cwd);
+            }
+        }
+    }
+    for (name, instance) in file.instances.iter().flatten() {
+        check_instance_name(name)?;
+        instance.env.iter().try_for_each(check_env)?;
+        for (process, overrides) in instance.processes.iter().flatten() {
+            overrides.env.iter().try_for_each(check_env)?;
+            if !file.processes.iter().any(|p| p.contains_key(process)) {
+                return Err(Error::InvalidConfig(format!(
+                    "instance {:?} overrides unknown process {:?}",
+                    name, process
+                )));
+            }
+        }
+    }
+    let kdf = match args.kdf {
+        KdfArg::Pbkdf2 => Kdf::PBKDF2,
+        KdfArg::Argon2id => Kdf::ARGON2ID,
+    };
+    let lock = match (args.password, args.key, args.recipient) {
+        (Some(password), _, _) => Some(Lock::Password { password, kdf }),
+        (_, Some(key), _) => Some(Lock::Key(keys::load_key(&key)?)),
+        (_, _, Some(recipient)) => Some(Lock::Recipient(keys::load_key(&recipient)?)),
+        _ => None,
+    };
+    let sign_key = args.sign_key.as_deref().map(keys::load_key).transpose()?;
+    let secrets = take_secrets(&mut file)?;
+    if secrets.is_some() && lock.is_none() && args.secrets_password.is_none() {
+        return Err(Error::InvalidConfig(
+            "secrets need a key, pass --secrets-password or encrypt the capsule".to_string(),
+        ));
+    }
+    file.build = Some(BuildInfo {
+        target: target.clone(),
+        // runtimes are built from the same workspace
+        runtime_version: env!("CARGO_PKG_VERSION").to_string(),
+    });
 
     let base = input_path
         .parent()
         .ok_or(Error::CouldNotReadFile(input_path.display().to_string()))?;
 
-    let input_bytes = to_binary(file, base).ok_or(Error::InvalidDataFormat)?;
-
-    let mut file = File::create(&output_path)
+    let output = File::create(&output_path)
         .set_error(Error::CouldNotWriteFile(output_path.display().to_string()))?;
-
-    let (input_bytes, magic_number) = if let Some(password) = args.password {
-        let (mut salt, mut nonce_bytes, mut ciphertext) =
-            encrypt(&password, &input_bytes).set_error(Error::CouldNotEncryptFile)?;
-        salt.append(&mut nonce_bytes);
-        salt.append(&mut ciphertext);
-        (salt, MAGIC_NUMBER_ENCRYPTED)
-    } else {
-        (input_bytes, MAGIC_NUMBER_PLAIN)
-    };
-
-    (|| {
-        file.write_all(runtime)?;
-        file.write_all(&input_bytes)?;
-        file.write_all(&(input_bytes.len() as u64).to_le_bytes())?;
-        file.write_all(magic_number)?;
-        Ok::<_, std::io::Error>(())
-    })()
-    .set_error(Error::InternalError)?;
+    let mut payload = PayloadWriter::new(BufWriter::new(output), runtime, lock.as_ref())?;
+    if let Some(secrets) = secrets {
+        let password = match &lock {
+            Some(Lock::Password { password, .. }) => Some(password.as_str()),
+            _ => None,
+        };
+        let secrets_password = args.secrets_password.as_deref();
+        file.sealed_secrets = Some(seal_secrets(
+            secrets,
+            payload.key(),
+            secrets_password.filter(|s| Some(*s) != password),
+            kdf,
+        )?);
+    }
+    let file = embed_files(file, base, &mut payload)?;
+    payload.finish(file, sign_key.as_ref())?;
 
     make_executable(&output_path).ok_or(Error::InternalError)?;
     Ok(())
@@ -144,65 +325,167 @@ fn make_executable(path: &Path) -> Option<()> {
     }
 }
 
-fn deserialize(file_data: &str) -> Option<Capsule> {
+/// Parses the config, what the compiler fills in is dropped so that it can not
+/// reach the signed manifest
+fn deserialize(file_data: &str) -> Result<Capsule, Error> {
+    let mut capsule = parse(file_data)?;
+    capsule.sealed_secrets = None;
+    capsule.blobs = None;
+    capsule.embedded = None;
+    capsule.build = None;
+    for process in capsule.processes.iter_mut().flat_map(|p| p.values_mut()) {
+        process.embedded = None;
+    }
+    Ok(capsule)
+}
+
+fn parse(file_data: &str) -> Result<Capsule, Error> {
     let json: Result<Capsule, _> = serde_json::from_str(file_data);
     match json {
-        Ok(json) => return Some(json),
+        Ok(json) => return Ok(json),
         Err(err) => {
             if !err.is_syntax() {
-                return None;
+                return Err(Error::InvalidConfig(err.to_string()));
             }
         }
     }
-    let toml: Result<Capsule, _> = toml::from_str(file_data);
-    if toml.is_ok() {
-        return Some(toml.unwrap());
+    toml::from_str(file_data).map_err(|e| Error::InvalidConfig(e.message().to_string()))
+}
+
+/// Global secrets and process name -> secrets of this process
+type ConfigSecrets = (
+    HashMap<String, Secret>,
+    HashMap<String, HashMap<String, Secret>>,
+);
+
+/// Moves the secrets out of the config, they never reach the manifest
+fn take_secrets(c: &mut Capsule) -> Result<Option<ConfigSecrets>, Error> {
+    let global = c.secrets.take().unwrap_or_default();
+    let mut processes = HashMap::new();
+    for (name, process) in c.processes.iter_mut().flatten() {
+        if let Some(unknown) = process
+            .use_secrets
+            .iter()
+            .flatten()
+            .find(|used| !global.contains_key(*used))
+        {
+            return Err(Error::InvalidConfig(format!(
+                "process {:?} uses unknown secret {:?}",
+                name, unknown
+            )));
+        }
+        if let Some(secrets) = process.secrets.take() {
+            processes.insert(name.clone(), secrets);
+        }
+    }
+    if global.is_empty() && processes.is_empty() {
+        return Ok(None);
     }
-    return None;
+    Ok(Some((global, processes)))
 }
 
-fn to_binary(mut c: Capsule, base: &Path) -> Option<Vec<u8>> {
-    let buff = std::io::Cursor::new(Vec::new());
-    let mut zip_file = ZipWriter::new(buff);
-    let mut new_mapping = HashMap::new();
-    if let Some(files) = &c.files {
-        write_files(&mut zip_file, &mut new_mapping, files, base)?;
-        c.files = Some(new_mapping);
+/// Seals the secrets with their own password, or with the payload key
+fn seal_secrets(
+    (global, processes): ConfigSecrets,
+    payload_key: Option<&[u8; KEY_SIZE]>,
+    password: Option<&str>,
+    kdf: Kdf,
+) -> Result<SealedSecrets, Error> {
+    let sealed_with = match password {
+        Some(_) => SecretsKey::password(kdf),
+        None => SecretsKey::Payload,
+    };
+    let key = sealed_with.resolve(payload_key, password)?;
+    SealedSecrets::seal(&key, sealed_with, global, processes)
+}
+
+/// Streams every referenced file into the payload
+fn embed_files(
+    mut c: Capsule,
+    base: &Path,
+    payload: &mut PayloadWriter<impl Write>,
+) -> Result<Capsule, Error> {
+    let exclude = c.exclude.clone().unwrap_or_default();
+    if let Some(files) = c.files.take() {
+        let filter = Filter::new(base, &exclude)?;
+        c.embedded = Some(write_files(payload, expand(&files, base, &filter)?)?);
     }
     if let Some(processes) = &mut c.processes {
         for process in processes.values_mut() {
-            if let Some(files) = &process.files {
-                let mut new_mapping = HashMap::new();
-                write_files(&mut zip_file, &mut new_mapping, files, base)?;
-                process.files = Some(new_mapping);
+            if let Some(files) = process.files.take() {
+                let mut exclude = exclude.clone();
+                exclude.extend(process.exclude.iter().flatten().cloned());
+                let filter = Filter::new(base, &exclude)?;
+                process.embedded = Some(write_files(payload, expand(&files, base, &filter)?)?);
             }
         }
     }
-    let writer = zip_file.finish().ok()?;
-    let zip_bytes = writer.into_inner();
-    c.fs = Some(zip_bytes);
-    postcard::to_allocvec(&c).ok()
+    Ok(c)
 }
 
 fn write_files(
-    zip_file: &mut ZipWriter<std::io::Cursor<Vec<u8>>>,
-    new_mapping: &mut HashMap<String, String>,
-    files: &HashMap<String, String>,
-    cwd: &Path,
-) -> Option<()> {
-    Some(for (local_path, target) in files {
-        let local_path = PathBuf::from(local_path);
-        let local_path = if local_path.is_absolute() {
-            local_path
-        } else {
-            cwd.join(local_path)
-        };
-        let bytes = fs::read(local_path).ok()?;
-        let random_name = Uuid::new_v4().to_string();
-        zip_file
-            .start_file(&random_name, SimpleFileOptions::default())
-            .ok()?;
-        zip_file.write_all(&bytes).ok()?;
-        new_mapping.insert(random_name, target.to_string());
-    })
+    payload: &mut PayloadWriter<impl Write>,
+    files: Vec<Entry>,
+) -> Result<Vec<EmbeddedFile>, Error> {
+    let mut embedded = vec![];
+    for entry in files {
+        let could_not_read = || Error::CouldNotReadFile(entry.source.display().to_string());
+        if let Some(symlink) = entry.symlink {
+            let metadata = fs::symlink_metadata(&entry.source).map_err(|_| could_not_read())?;
+            embedded.push(EmbeddedFile {
+                target: entry.target,
+                blob: None,
+                symlink: Some(symlink),
+                mode: None,
+                mtime: metadata.modified().ok(),
+            });
+            continue;
+        }
+        let mut file = File::open(&entry.source).map_err(|_| could_not_read())?;
+        let metadata = file.metadata().map_err(|_| could_not_read())?;
+        let blob = payload.add(&mut file).map_err(|_| could_not_read())?;
+        embedded.push(EmbeddedFile {
+            target: entry.target,
+            blob: Some(blob),
+            symlink: None,
+            mode: entry.mode.or(file_mode(&metadata)),
+            mtime: metadata.modified().ok(),
+        });
+    }
+    Ok(embedded)
+}
+
+/// Unix permission bits, files authored on Windows can set a `mode` in the config
+fn file_mode(metadata: &fs::Metadata) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Some(metadata.permissions().mode() & 0o7777)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::deserialize;
+
+    #[test]
+    fn compiler_fields_are_not_read_from_the_config() {
+        let capsule = deserialize(
+            r#"{
+                "version": "1.0.0",
+                "blobs": {"h": {"offset": 0, "size": 1}},
+                "embedded": [{"target": "x", "blob": "h"}],
+                "processes": {"app": {"cmd": "app", "embedded": [{"target": "y", "blob": "h"}]}}
+            }"#,
+        )
+        .unwrap();
+        assert!(capsule.blobs.is_none());
+        assert!(capsule.embedded.is_none());
+        assert!(capsule.processes.unwrap()["app"].embedded.is_none());
+    }
 }
@@ -0,0 +1,242 @@
+//! Builds capsules with the compiler binary and checks what they extract.
+//!
+//! Needs the runtime of the host target to be embedded in the compiler, the
+//! tests fail otherwise: build it with `cargo build --release -p
+//! capsules_runtime --target <host target>` first.
+
+use capsules_lib::scratch::Scratch;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn host_target() -> &'static str {
+    match (env::consts::OS, env::consts::ARCH) {
+        ("linux", "x86_64") => "x86_64-unknown-linux-gnu",
+        ("linux", "aarch64") => "aarch64-unknown-linux-gnu",
+        ("linux", "arm") => "armv7-unknown-linux-gnueabihf",
+        ("macos", "x86_64") => "x86_64-apple-darwin",
+        ("macos", "aarch64") => "aarch64-apple-darwin",
+        ("windows", _) => "x86_64-pc-windows-gnu",
+        _ => "unsupported",
+    }
+}
+
+fn run(cmd: &mut Command) -> Output {
+    let output = cmd.output().unwrap();
+    assert!(
+        output.status.success(),
+        "{:?} failed: {}",
+        cmd,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+/// Compiles `config` into `dir`
+fn build(config: &Path, dir: &Path, password: Option<&str>) -> PathBuf {
+    let capsule = dir.join(format!("capsule{}", env::consts::EXE_SUFFIX));
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_capsules_compiler"));
+    cmd.arg("-i")
+        .arg(config)
+        .args(["-t", host_target()])
+        .arg("-o")
+        .arg(&capsule);
+    if let Some(password) = password {
+        cmd.args(["-p", password]);
+    }
+    let output = cmd.output().unwrap();
+    assert!(
+        output.status.success(),
+        "compiling for {} failed, is its runtime embedded? {}",
+        host_target(),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    capsule
+}
+
+/// Writes `config` to `dir/capsule.json` and compiles it
+fn build_config(config: serde_json::Value, dir: &Scratch, password: Option<&str>) -> PathBuf {
+    let path = dir.write("capsule.json", config.to_string());
+    build(&path, dir, password)
+}
+
+/// Extracts from another working directory, which has to stay empty
+fn extract(capsule: &Path, dir: &Path) -> PathBuf {
+    let cwd = dir.join("cwd");
+    let out = dir.join("out");
+    fs::create_dir_all(&cwd).unwrap();
+    run(Command::new(capsule)
+        .current_dir(&cwd)
+        .arg("extract")
+        .arg("--to")
+        .arg(&out));
+    assert_eq!(fs::read_dir(&cwd).unwrap().count(), 0);
+    out
+}
+
+/// Files under `root`, relative and with forward slashes, sorted
+fn tree(root: &Path) -> Vec<String> {
+    fn walk(root: &Path, dir: &Path, files: &mut Vec<String>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                walk(root, &path, files);
+            } else {
+                let relative = path.strip_prefix(root).unwrap();
+                files.push(relative.to_string_lossy().replace('\\', "/"));
+            }
+        }
+    }
+    let mut files = vec![];
+    walk(root, root, &mut files);
+    files.sort();
+    files
+}
+
+fn test_project() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .unwrap()
+        .join("test_project")
+}
+
+#[test]
+fn test_project_extracts_under_the_process_cwd() {
+    let dir = Scratch::new("test-project");
+    let capsule = build(&test_project().join("capsule.json"), &dir, None);
+    let out = extract(&capsule, &dir);
+
+    assert_eq!(tree(&out), ["node/index.js"]);
+    assert_eq!(
+        fs::read(out.join("node/index.js")).unwrap(),
+        fs::read(test_project().join("index.js")).unwrap()
+    );
+}
+
+#[test]
+fn global_and_process_files_share_the_capsule_root() {
+    let dir = Scratch::new("capsule-root");
+    let index = test_project()
+        .join("index.js")
+        .to_string_lossy()
+        .to_string();
+    let json = serde_json::json!({
+        "version": "1.0.0",
+        "files": { (index.clone()): "shared/index.js" },
+        "processes": {
+            "api": {
+                "cmd": "bun",
+                "cwd": "apps/api",
+                "files": { (index.clone()): "src/index.js" }
+            },
+            "worker": {
+                "cmd": "bun",
+                "files": { (index.clone()): "index.js" }
+            }
+        }
+    });
+    let capsule = build_config(json, &dir, None);
+    let out = extract(&capsule, &dir);
+
+    assert_eq!(
+        tree(&out),
+        [
+            "apps/api/src/index.js",
+            "shared/index.js",
+            "worker/index.js"
+        ]
+    );
+}
+
+/// Tears the supervisor down when the test ends, even on failure
+struct Supervisor<'a> {
+    capsule: &'a Path,
+    data_dir: PathBuf,
+}
+
+impl Supervisor<'_> {
+    fn command(&self, args: &[&str]) -> Command {
+        let mut cmd = Command::new(self.capsule);
+        cmd.arg("--data-dir").arg(&self.data_dir).args(args);
+        cmd
+    }
+}
+
+impl Drop for Supervisor<'_> {
+    fn drop(&mut self) {
+        let _ = self.command(&["daemon", "tear-down"]).output();
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn processes_do_not_see_the_password() {
+    const PASSWORD: &str = "correct horse battery staple";
+    let dir = Scratch::new("password");
+    // the parent of the process is the supervisor
+    let script = "env > env.txt; tr '\\0' '\\n' < /proc/$PPID/environ > supervisor.txt; \
+                  touch done; exec sleep 60";
+    let json = serde_json::json!({
+        "version": "1.0.0",
+        "processes": { "dump": { "cmd": "sh", "args": ["-c", script] } }
+    });
+    let capsule = build_config(json, &dir, Some(PASSWORD));
+    // the manifest can not be read without the password
+    let mut info = Command::new(&capsule)
+        .arg("info")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    writeln!(info.stdin.take().unwrap(), "not the password").unwrap();
+    assert_eq!(info.wait().unwrap().code(), Some(6));
+
+    // dropped before `dir`, which holds its data dir
+    let supervisor = Supervisor {
+        capsule: &capsule,
+        data_dir: dir.join("data"),
+    };
+
+    // the variable older versions used must be scrubbed as well
+    let mut start = supervisor
+        .command(&["daemon", "start"])
+        .env("__SUPERVISOR_PASSWORD__", PASSWORD)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    writeln!(start.stdin.take().unwrap(), "{}", PASSWORD).unwrap();
+    let output = start.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "daemon start failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let dump = supervisor.data_dir.join("dump");
+    let started = Instant::now();
+    while !dump.join("done").exists() {
+        assert!(
+            started.elapsed() < Duration::from_secs(120),
+            "process did not start"
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+    let env = fs::read_to_string(dump.join("env.txt")).unwrap();
+    assert!(env.contains("CAPSULE_DIR="));
+    for file in ["env.txt", "supervisor.txt"] {
+        let env = fs::read_to_string(dump.join(file)).unwrap();
+        assert!(!env.contains(PASSWORD), "password found in {}", file);
+        assert!(
+            !env.contains("__SUPERVISOR_PASSWORD__"),
+            "found in {}",
+            file
+        );
+    }
+}
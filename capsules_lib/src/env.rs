@@ -0,0 +1,264 @@
+//! `${NAME}` and `${NAME:-default}` references in env values, resolved by the
+//! supervisor against the other capsule vars, the built-ins and the host env.
+
+use crate::{Env, Error};
+
+/// Data directory of the running instance
+pub const CAPSULE_DIR: &str = "CAPSULE_DIR";
+/// Working directory of the process
+pub const PROCESS_CWD: &str = "PROCESS_CWD";
+/// Name of the running instance
+pub const CAPSULE_INSTANCE: &str = "CAPSULE_INSTANCE";
+/// Directory of the file secrets of the process, only set when it has some
+pub const CAPSULE_SECRETS_DIR: &str = "CAPSULE_SECRETS_DIR";
+
+pub(crate) fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Replaces the references of `value` with what `lookup` returns, unset
+/// names expand to nothing. The default is used when the name is unset or
+/// empty and is taken literally. `$$` is a literal `$`.
+fn interpolate<F>(key: &str, value: &str, mut lookup: F) -> Result<String, Error>
+where
+    F: FnMut(&str) -> Result<Option<String>, Error>,
+{
+    let invalid = |reason: String| Error::InvalidEnv(key.to_string(), reason);
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+            continue;
+        }
+        let Some(body) = rest.strip_prefix('{') else {
+            out.push('$');
+            continue;
+        };
+        let end = body
+            .find('}')
+            .ok_or_else(|| invalid(format!("unterminated reference in {:?}", value)))?;
+        let (name, default) = match body[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&body[..end], None),
+        };
+        if !is_name(name) {
+            return Err(invalid(format!("invalid variable name {:?}", name)));
+        }
+        let found = lookup(name)?.filter(|v| default.is_none() || !v.is_empty());
+        match (found, default) {
+            (Some(found), _) => out.push_str(&found),
+            (None, Some(default)) => out.push_str(default),
+            (None, None) => (),
+        }
+        rest = &body[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Checks the syntax of the references, without resolving them
+pub fn check_env(env: &Env) -> Result<(), Error> {
+    for (key, value) in env {
+        interpolate(key, value, |_| Ok(None))?;
+    }
+    Ok(())
+}
+
+/// Resolves the references of every value of `env`. A name that is a key of
+/// `env` expands to its resolved value, any other name is looked up in `host`.
+/// A key referencing itself, like `PATH: "/opt/bin:${PATH}"`, gets the host
+/// value, longer cycles are an error.
+pub fn resolve_env(env: &Env, host: impl Fn(&str) -> Option<String>) -> Result<Env, Error> {
+    struct Resolver<'a, H> {
+        env: &'a Env,
+        host: H,
+        resolved: Env,
+        stack: Vec<&'a str>,
+    }
+
+    impl<'a, H: Fn(&str) -> Option<String>> Resolver<'a, H> {
+        fn get(&mut self, name: &str) -> Result<Option<String>, Error> {
+            if let Some(value) = self.resolved.get(name) {
+                return Ok(Some(value.clone()));
+            }
+            let env = self.env;
+            let Some((key, raw)) = env.get_key_value(name) else {
+                return Ok((self.host)(name));
+            };
+            if self.stack.last() == Some(&key.as_str()) {
+                return Ok((self.host)(name));
+            }
+            if self.stack.contains(&key.as_str()) {
+                return Err(Error::InvalidEnv(
+                    key.clone(),
+                    format!("circular reference through {}", self.stack.join(", ")),
+                ));
+            }
+            self.stack.push(key);
+            let value = interpolate(key, raw, |name| self.get(name))?;
+            self.stack.pop();
+            self.resolved.insert(key.clone(), value.clone());
+            Ok(Some(value))
+        }
+    }
+
+    let mut resolver = Resolver {
+        env,
+        host,
+        resolved: Env::new(),
+        stack: vec![],
+    };
+    for key in env.keys() {
+        resolver.get(key)?;
+    }
+    Ok(resolver.resolved)
+}
+
+/// Whether host var `name` is passed to processes, patterns are names or
+/// prefixes ending with `*`, e.g. `LC_*`
+pub fn passthrough_allows(patterns: &[String], name: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => pattern == name,
+        })
+}
+
+/// Parses a dotenv file: `KEY=VALUE` lines, optionally starting with `export`.
+/// Blank lines and `#` comments are skipped. Values can be quoted, double
+/// quoted ones understand `\n`, `\"` and `\\`.
+pub fn parse_dotenv(content: &str) -> Result<Env, Error> {
+    let mut env = Env::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid =
+            || Error::InvalidConfig(format!("line {} of the env file is not KEY=VALUE", i + 1));
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line.split_once('=').ok_or_else(invalid)?;
+        let key = key.trim();
+        if !is_name(key) {
+            return Err(invalid());
+        }
+        let value = value.trim();
+        let quoted = |quote: char| {
+            value
+                .strip_prefix(quote)
+                .and_then(|v| v.strip_suffix(quote))
+        };
+        let value = if let Some(value) = quoted('"') {
+            unescape(value)
+        } else if let Some(value) = quoted('\'') {
+            value.to_string()
+        } else {
+            // an inline comment needs a space before the #
+            match value.find(" #") {
+                Some(end) => value[..end].trim_end().to_string(),
+                None => value.to_string(),
+            }
+        };
+        env.insert(key.to_string(), value);
+    }
+    Ok(env)
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => out.push('\n'),
+            ('\\', Some(escaped @ ('"' | '\\'))) => out.push(escaped),
+            _ => {
+                out.push(c);
+                continue;
+            }
+        }
+        chars.next();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_env, parse_dotenv, passthrough_allows, resolve_env};
+    use crate::Error;
+    use std::collections::HashMap;
+
+    #[test]
+    fn env_references_are_resolved() {
+        let env: HashMap<String, String> = serde_json::from_str(
+            r#"{
+                "HOST": "db",
+                "URL": "postgres://${HOST}:${PORT:-5432}/${CAPSULE_INSTANCE}",
+                "PATH": "/opt/app/bin:${PATH}",
+                "PRICE": "$$5 and $HOME",
+                "EMPTY": "${UNSET}"
+            }"#,
+        )
+        .unwrap();
+        let host = |name: &str| match name {
+            "PATH" => Some("/usr/bin".to_string()),
+            "CAPSULE_INSTANCE" => Some("canary".to_string()),
+            _ => None,
+        };
+        let resolved = resolve_env(&env, host).unwrap();
+        assert_eq!(resolved["URL"], "postgres://db:5432/canary");
+        assert_eq!(resolved["PATH"], "/opt/app/bin:/usr/bin");
+        assert_eq!(resolved["PRICE"], "$5 and $HOME");
+        assert_eq!(resolved["EMPTY"], "");
+
+        let cycle = HashMap::from([
+            ("A".to_string(), "${B}".to_string()),
+            ("B".to_string(), "${A}".to_string()),
+        ]);
+        assert!(matches!(
+            resolve_env(&cycle, host),
+            Err(Error::InvalidEnv(_, _))
+        ));
+        let unterminated = HashMap::from([("A".to_string(), "${B".to_string())]);
+        assert!(check_env(&unterminated).is_err());
+        let bad_name = HashMap::from([("A".to_string(), "${1B}".to_string())]);
+        assert!(check_env(&bad_name).is_err());
+
+        let allowed = ["HOME".to_string(), "LC_*".to_string()];
+        assert!(passthrough_allows(&allowed, "LC_ALL"));
+        assert!(passthrough_allows(&allowed, "HOME"));
+        assert!(!passthrough_allows(&allowed, "HOMEDIR"));
+        assert!(!passthrough_allows(&allowed, "__SUPERVISOR_PASSWORD__"));
+    }
+
+    #[test]
+    fn dotenv_files_are_parsed() {
+        let env = parse_dotenv(
+            r#"
+            # database
+            export DB_HOST=db.internal
+            DB_PORT = 5432 # default port
+            GREETING="hello\n\"world\""
+            RAW='single \n # kept'
+            EMPTY=
+            "#,
+        )
+        .unwrap();
+        assert_eq!(env.len(), 5);
+        assert_eq!(env["DB_HOST"], "db.internal");
+        assert_eq!(env["DB_PORT"], "5432");
+        assert_eq!(env["GREETING"], "hello\n\"world\"");
+        assert_eq!(env["RAW"], "single \\n # kept");
+        assert_eq!(env["EMPTY"], "");
+        assert!(parse_dotenv("NO_EQUALS").is_err());
+        assert!(parse_dotenv("1BAD=x").is_err());
+    }
+}
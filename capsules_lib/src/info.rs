@@ -0,0 +1,229 @@
+//! Summary of a built capsule, read from its manifest without starting it.
+//! Printed by `capsule inspect` on the compiler and `info` on the runtime.
+
+use crate::payload::Scheme;
+use crate::{Capsule, EmbeddedFile};
+use bytesize::ByteSize;
+use serde::Serialize;
+use std::fmt::Display;
+
+/// Length of the content hashes in the text output
+const SHORT_HASH: usize = 12;
+
+#[derive(Serialize)]
+pub struct CapsuleInfo {
+    pub version: String,
+    /// None for capsules built before the target was recorded
+    pub target: Option<String>,
+    pub runtime_version: Option<String>,
+    pub encrypted: bool,
+    /// How the key is obtained, e.g. "password (argon2id)" or "none"
+    pub encryption: String,
+    /// Sorted, values are never included
+    pub env_keys: Vec<String>,
+    /// Global secrets, sorted, values are never included
+    pub secrets: Vec<String>,
+    /// Sorted by name
+    pub processes: Vec<ProcessInfo>,
+    /// Sorted by path
+    pub files: Vec<FileInfo>,
+}
+
+#[derive(Serialize)]
+pub struct ProcessInfo {
+    pub name: String,
+    pub command: Vec<String>,
+    pub cwd: String,
+    /// Sorted, values are never included
+    pub env_keys: Vec<String>,
+    /// Secrets given to the process, sorted
+    pub secrets: Vec<String>,
+    pub schedule: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct FileInfo {
+    /// Relative to the capsule directory
+    pub path: String,
+    pub size_bytes: Option<u64>,
+    pub sha256: Option<String>,
+    /// Octal, e.g. "0755"
+    pub mode: Option<String>,
+    pub symlink: Option<String>,
+}
+
+fn sorted_keys<'a>(keys: impl Iterator<Item = &'a String>) -> Vec<String> {
+    let mut keys: Vec<_> = keys.cloned().collect();
+    keys.sort();
+    keys
+}
+
+impl CapsuleInfo {
+    pub fn new(capsule: &Capsule, scheme: &Scheme) -> Self {
+        let file_info = |root: Option<&str>, file: &EmbeddedFile| {
+            let size = file
+                .blob
+                .as_ref()
+                .and_then(|hash| capsule.blobs.as_ref()?.get(hash))
+                .map(|blob| blob.size);
+            FileInfo {
+                path: match root {
+                    Some(root) => format!("{}/{}", root, file.target),
+                    None => file.target.clone(),
+                },
+                size_bytes: size,
+                sha256: file.blob.clone(),
+                mode: file.mode.map(|m| format!("{:04o}", m)),
+                symlink: file.symlink.clone(),
+            }
+        };
+
+        let mut processes = vec![];
+        let mut files: Vec<_> = capsule
+            .embedded
+            .iter()
+            .flatten()
+            .map(|f| file_info(None, f))
+            .collect();
+        for (name, process) in capsule.processes.iter().flatten() {
+            let cwd = process.cwd.clone().unwrap_or_else(|| name.clone());
+            files.extend(
+                process
+                    .embedded
+                    .iter()
+                    .flatten()
+                    .map(|f| file_info(Some(&cwd), f)),
+            );
+            let mut command = vec![process.cmd.clone()];
+            command.extend(process.args.iter().flatten().cloned());
+            processes.push(ProcessInfo {
+                name: name.clone(),
+                command,
+                cwd,
+                env_keys: sorted_keys(process.env.iter().flat_map(|e| e.keys())),
+                secrets: capsule
+                    .sealed_secrets
+                    .iter()
+                    .flat_map(|s| s.names(name, process.use_secrets.as_deref().unwrap_or_default()))
+                    .map(|(name, _)| name)
+                    .collect(),
+                schedule: process.schedule.as_ref().map(|s| s.to_string()),
+            });
+        }
+        processes.sort_by(|a, b| a.name.cmp(&b.name));
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let build = capsule.build.as_ref();
+        CapsuleInfo {
+            version: capsule.version.to_string(),
+            target: build.map(|b| b.target.clone()),
+            runtime_version: build.map(|b| b.runtime_version.clone()),
+            encrypted: scheme.is_encrypted(),
+            encryption: scheme.to_string(),
+            env_keys: sorted_keys(capsule.env.iter().flat_map(|e| e.keys())),
+            secrets: sorted_keys(capsule.sealed_secrets.iter().flat_map(|s| s.global.keys())),
+            processes,
+            files,
+        }
+    }
+}
+
+fn masked(keys: &[String]) -> String {
+    match keys.is_empty() {
+        true => "-".to_string(),
+        false => keys
+            .iter()
+            .map(|k| format!("{}=***", k))
+            .collect::<Vec<_>>()
+            .join(", "),
+    }
+}
+
+/// Left aligned columns, two spaces apart
+fn write_columns(f: &mut std::fmt::Formatter<'_>, rows: &[Vec<String>]) -> std::fmt::Result {
+    let mut widths = vec![];
+    for row in rows {
+        widths.resize(widths.len().max(row.len()), 0);
+        for (i, cell) in row.iter().enumerate() {
+            widths[i] = widths[i].max(cell.chars().count());
+        }
+    }
+    for row in rows {
+        let line: Vec<_> = row
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| format!("{:width$}", cell, width = widths[i]))
+            .collect();
+        writeln!(f, "  {}", line.join("  ").trim_end())?;
+    }
+    Ok(())
+}
+
+impl Display for CapsuleInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let dash = || "-".to_string();
+        let rows = [
+            ("Version", self.version.clone()),
+            ("Target", self.target.clone().unwrap_or_else(dash)),
+            (
+                "Runtime version",
+                self.runtime_version.clone().unwrap_or_else(dash),
+            ),
+            ("Encryption", self.encryption.clone()),
+            ("Env", masked(&self.env_keys)),
+            ("Secrets", masked(&self.secrets)),
+        ];
+        let width = rows.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+        for (key, value) in rows {
+            writeln!(f, "{:width$}: {}", key, value, width = width)?;
+        }
+
+        writeln!(f, "\nProcesses:")?;
+        let mut rows = vec![vec![
+            "Name".to_string(),
+            "Command".to_string(),
+            "Cwd".to_string(),
+            "Schedule".to_string(),
+            "Env".to_string(),
+            "Secrets".to_string(),
+        ]];
+        rows.extend(self.processes.iter().map(|p| {
+            vec![
+                p.name.clone(),
+                p.command.join(" "),
+                p.cwd.clone(),
+                p.schedule.clone().unwrap_or_else(dash),
+                masked(&p.env_keys),
+                masked(&p.secrets),
+            ]
+        }));
+        write_columns(f, &rows)?;
+
+        let total: u64 = self.files.iter().filter_map(|file| file.size_bytes).sum();
+        writeln!(f, "\nFiles ({}, {}):", self.files.len(), ByteSize::b(total))?;
+        let mut rows = vec![vec![
+            "Path".to_string(),
+            "Size".to_string(),
+            "Mode".to_string(),
+            "SHA-256".to_string(),
+        ]];
+        rows.extend(self.files.iter().map(|file| {
+            let path = match &file.symlink {
+                Some(link) => format!("{} -> {}", file.path, link),
+                None => file.path.clone(),
+            };
+            vec![
+                path,
+                file.size_bytes
+                    .map(|s| ByteSize::b(s).to_string())
+                    .unwrap_or_else(dash),
+                file.mode.clone().unwrap_or_else(dash),
+                file.sha256
+                    .as_ref()
+                    .map(|h| h.chars().take(SHORT_HASH).collect())
+                    .unwrap_or_else(dash),
+            ]
+        }));
+        write_columns(f, &rows)
+    }
+}
@@ -0,0 +1,286 @@
+//! Keys sealing payloads and secrets: derived from a password, given as is, or
+//! agreed with the X25519 key of the host running the capsule. Keys are 32
+//! bytes written as 64 hex characters.
+
+use crate::{Error, SetError};
+use argon2::{Algorithm, Argon2, Params, Version};
+use hkdf::Hkdf;
+use pbkdf2::pbkdf2_hmac;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+pub const KEY_SIZE: usize = 32;
+pub const SALT_SIZE: usize = 16;
+
+/// Binds the payload key to its use and to both public keys
+const RECIPIENT_INFO: &[u8] = b"capsules payload key v1";
+
+/// Password derivation, the parameters are stored next to the salt
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Kdf {
+    Pbkdf2Sha256 {
+        iterations: u32,
+    },
+    Argon2id {
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+}
+
+impl Kdf {
+    pub const PBKDF2: Kdf = Kdf::Pbkdf2Sha256 {
+        iterations: 600_000,
+    };
+    /// OWASP recommendation, a fraction of a second on small boards
+    pub const ARGON2ID: Kdf = Kdf::Argon2id {
+        memory_kib: 19 * 1024,
+        iterations: 2,
+        parallelism: 1,
+    };
+
+    /// Highest costs accepted, the parameters come from the unauthenticated
+    /// header and would otherwise let a modified capsule exhaust the host
+    pub const MAX_PBKDF2_ITERATIONS: u32 = 10_000_000;
+    pub const MAX_ARGON2_MEMORY_KIB: u32 = 1024 * 1024;
+    pub const MAX_ARGON2_ITERATIONS: u32 = 16;
+    pub const MAX_ARGON2_PARALLELISM: u32 = 16;
+
+    /// Err when the parameters cost more than the maximums
+    pub fn check(&self) -> Result<(), Error> {
+        let in_bounds = match *self {
+            Kdf::Pbkdf2Sha256 { iterations } => iterations <= Self::MAX_PBKDF2_ITERATIONS,
+            Kdf::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => {
+                memory_kib <= Self::MAX_ARGON2_MEMORY_KIB
+                    && iterations <= Self::MAX_ARGON2_ITERATIONS
+                    && parallelism <= Self::MAX_ARGON2_PARALLELISM
+            }
+        };
+        match in_bounds {
+            true => Ok(()),
+            false => Err(Error::InvalidDataFormat),
+        }
+    }
+
+    pub fn derive(&self, password: &str, salt: &[u8]) -> Result<[u8; KEY_SIZE], Error> {
+        self.check()?;
+        let mut key = [0u8; KEY_SIZE];
+        match *self {
+            Kdf::Pbkdf2Sha256 { iterations } => {
+                pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut key)
+            }
+            Kdf::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => {
+                let params = Params::new(memory_kib, iterations, parallelism, Some(KEY_SIZE))
+                    .set_error(Error::InvalidDataFormat)?;
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(password.as_bytes(), salt, &mut key)
+                    .set_error(Error::InvalidDataFormat)?;
+            }
+        }
+        Ok(key)
+    }
+}
+
+impl Display for Kdf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Kdf::Pbkdf2Sha256 { .. } => write!(f, "pbkdf2-sha256"),
+            Kdf::Argon2id { .. } => write!(f, "argon2id"),
+        }
+    }
+}
+
+pub fn salt() -> [u8; SALT_SIZE] {
+    let mut salt = [0u8; SALT_SIZE];
+    rand::rng().fill_bytes(&mut salt);
+    salt
+}
+
+/// A random key, usable as a raw key or an X25519 private key
+pub fn generate_key() -> [u8; KEY_SIZE] {
+    let mut key = [0u8; KEY_SIZE];
+    rand::rng().fill_bytes(&mut key);
+    key
+}
+
+pub fn public_key(private: &[u8; KEY_SIZE]) -> [u8; KEY_SIZE] {
+    PublicKey::from(&StaticSecret::from(*private)).to_bytes()
+}
+
+/// Parses 64 hex characters, surrounding whitespace is ignored
+pub fn parse_key(hex: &str) -> Option<[u8; KEY_SIZE]> {
+    let hex = hex.trim();
+    if hex.len() != KEY_SIZE * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0u8; KEY_SIZE];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+/// A key given on the command line, in hex or as a file holding the hex or
+/// the 32 raw bytes
+pub fn load_key(arg: &str) -> Result<[u8; KEY_SIZE], Error> {
+    if let Some(key) = parse_key(arg) {
+        return Ok(key);
+    }
+    let content = fs::read(Path::new(arg)).set_error(Error::CouldNotReadFile(arg.to_string()))?;
+    if let Ok(key) = content.as_slice().try_into() {
+        return Ok(key);
+    }
+    std::str::from_utf8(&content)
+        .ok()
+        .and_then(parse_key)
+        .ok_or_else(|| Error::InvalidConfig(format!("{:?} does not hold a 32 byte key", arg)))
+}
+
+/// Key of a payload sealed for `recipient`: a fresh ephemeral key agreed with
+/// the recipient one, then HKDF-SHA256. Returns the ephemeral public key and
+/// the payload key
+pub fn seal_for(recipient: &[u8; KEY_SIZE]) -> Result<([u8; KEY_SIZE], [u8; KEY_SIZE]), Error> {
+    let ephemeral = generate_key();
+    let ephemeral_public = public_key(&ephemeral);
+    let key = agree(&ephemeral, recipient, &ephemeral_public, recipient)
+        .ok_or_else(|| Error::InvalidConfig("invalid recipient public key".to_string()))?;
+    Ok((ephemeral_public, key))
+}
+
+/// Payload key of `seal_for`, on the host holding the recipient private key
+pub fn open_for(
+    private: &[u8; KEY_SIZE],
+    ephemeral: &[u8; KEY_SIZE],
+    recipient: &[u8; KEY_SIZE],
+) -> Result<[u8; KEY_SIZE], Error> {
+    if public_key(private) != *recipient {
+        return Err(Error::InvalidPassword);
+    }
+    agree(private, ephemeral, ephemeral, recipient).ok_or(Error::InvalidDataFormat)
+}
+
+fn agree(
+    private: &[u8; KEY_SIZE],
+    public: &[u8; KEY_SIZE],
+    ephemeral: &[u8; KEY_SIZE],
+    recipient: &[u8; KEY_SIZE],
+) -> Option<[u8; KEY_SIZE]> {
+    let shared = StaticSecret::from(*private).diffie_hellman(&PublicKey::from(*public));
+    // low order public keys give a shared secret anyone can compute
+    if !shared.was_contributory() {
+        return None;
+    }
+    let salt = [ephemeral.as_slice(), recipient.as_slice()].concat();
+    let mut key = [0u8; KEY_SIZE];
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+        .expand(RECIPIENT_INFO, &mut key)
+        .ok()?;
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Kdf, generate_key, load_key, open_for, parse_key, public_key, seal_for};
+    use crate::Error;
+    use crate::payload::hex;
+    use crate::scratch::Scratch;
+    use std::fs;
+
+    #[test]
+    fn keys_are_parsed_from_hex_or_files() {
+        let key = generate_key();
+        assert_eq!(parse_key(&format!(" {}\n", hex(&key))), Some(key));
+        assert_eq!(parse_key(&hex(&key)[2..]), None);
+        assert_eq!(parse_key(&"zz".repeat(32)), None);
+        assert_eq!(parse_key(&"é".repeat(32)), None);
+
+        let dir = Scratch::new("keys");
+        let raw = dir.write("raw.key", key);
+        assert_eq!(load_key(raw.to_str().unwrap()).unwrap(), key);
+        let text = dir.write("hex.key", format!("{}\n", hex(&key)));
+        assert_eq!(load_key(text.to_str().unwrap()).unwrap(), key);
+        fs::write(&text, "too short").unwrap();
+        assert!(matches!(
+            load_key(text.to_str().unwrap()),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn recipient_keys_agree() {
+        let private = generate_key();
+        let recipient = public_key(&private);
+        let (ephemeral, key) = seal_for(&recipient).unwrap();
+        assert_eq!(open_for(&private, &ephemeral, &recipient).unwrap(), key);
+        assert!(matches!(
+            open_for(&generate_key(), &ephemeral, &recipient),
+            Err(Error::InvalidPassword)
+        ));
+        // low order points would give a key anyone can compute
+        assert!(matches!(seal_for(&[0u8; 32]), Err(Error::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn kdfs_depend_on_the_password_and_salt() {
+        let kdfs = [
+            Kdf::Pbkdf2Sha256 { iterations: 1000 },
+            Kdf::Argon2id {
+                memory_kib: 64,
+                iterations: 1,
+                parallelism: 1,
+            },
+        ];
+        for kdf in kdfs {
+            let key = kdf.derive("secret", &[1u8; 16]).unwrap();
+            assert_eq!(kdf.derive("secret", &[1u8; 16]).unwrap(), key);
+            assert_ne!(kdf.derive("secret", &[2u8; 16]).unwrap(), key);
+            assert_ne!(kdf.derive("other", &[1u8; 16]).unwrap(), key);
+        }
+    }
+
+    #[test]
+    fn kdf_costs_are_bounded() {
+        assert!(Kdf::PBKDF2.check().is_ok());
+        assert!(Kdf::ARGON2ID.check().is_ok());
+        let costly = [
+            Kdf::Pbkdf2Sha256 {
+                iterations: u32::MAX,
+            },
+            Kdf::Argon2id {
+                memory_kib: u32::MAX,
+                iterations: 1,
+                parallelism: 1,
+            },
+            Kdf::Argon2id {
+                memory_kib: 64,
+                iterations: u32::MAX,
+                parallelism: 1,
+            },
+            Kdf::Argon2id {
+                memory_kib: 64,
+                iterations: 1,
+                parallelism: u32::MAX,
+            },
+        ];
+        for kdf in costly {
+            // refused before any work is done
+            assert!(matches!(
+                kdf.derive("secret", &[1u8; 16]),
+                Err(Error::InvalidDataFormat)
+            ));
+        }
+    }
+}
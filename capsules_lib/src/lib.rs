@@ -1,19 +1,32 @@
+pub mod env;
+pub mod info;
+pub mod keys;
+pub mod payload;
+#[cfg(any(test, feature = "testing"))]
+pub mod scratch;
+pub mod secrets;
+pub mod signing;
+
 use bytesize::ByteSize;
+use chrono::{DateTime, Local};
+use croner::Cron;
 use humanize_duration::prelude::DurationExt;
 use semver::Version;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::fmt::Display;
 
 use std::process::{self, Child};
-use std::time::{Duration, Instant};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
 
+use payload::Blob;
+use secrets::{SealedSecrets, Secret};
+
 use aes_gcm::aead::Aead;
 use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
-use pbkdf2::pbkdf2_hmac;
-use rand::RngCore;
+use rand::{Rng, RngCore};
 use serde::{Deserialize, Serialize};
-use sha2::Sha256;
 
 pub static ASCII_ART: &str = include_str!("./ascii_art.txt");
 
@@ -32,44 +45,33 @@ pub const RUNTIME_TARGETS: &[(&str, &str)] = &[
     ("x86_64-apple-darwin", ""),
 ];
 
-pub const MAGIC_NUMBER_PLAIN: &[u8; 8] = b"SETENV_P";
-pub const MAGIC_NUMBER_ENCRYPTED: &[u8; 8] = b"SETENV_E";
-pub const FOOTER_SIZE: i64 = 16;
-
-fn derive_key(password: &str, salt: &[u8]) -> [u8; 32] {
-    let mut key = [0u8; 32];
-    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, 600_000, &mut key);
-    key
-}
-
-pub fn encrypt(password: &str, plaintext: &[u8]) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), String> {
-    let mut salt = vec![0u8; 16];
-    rand::rng().fill_bytes(&mut salt);
+/// Ends every payload, the format version and the scheme are in front of it
+pub const MAGIC_NUMBER: &[u8; 8] = b"CAPSULES";
+pub const DEFAULT_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+pub const DEFAULT_LOG_MAX_FILES: u32 = 5;
+pub const DEFAULT_STOP_TIMEOUT_MS: u64 = 10_000;
+pub const DEFAULT_RESTART_DELAY: u64 = 10;
+pub const DEFAULT_BACKOFF_INITIAL: u64 = 1_000;
+pub const DEFAULT_BACKOFF_MAX: u64 = 60_000;
+pub const DEFAULT_BACKOFF_MULTIPLIER: f64 = 2.0;
 
-    let key_bytes = derive_key(password, &salt);
-    let key = Key::<Aes256Gcm>::from_slice(&key_bytes);
-    let cipher = Aes256Gcm::new(key);
+/// Returns the nonce and the ciphertext
+pub fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> Result<([u8; 12], Vec<u8>), Error> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
 
-    let mut nonce_bytes = vec![0u8; 12];
+    let mut nonce_bytes = [0u8; 12];
     rand::rng().fill_bytes(&mut nonce_bytes);
     let nonce = Nonce::from_slice(&nonce_bytes);
 
     let ciphertext = cipher
         .encrypt(nonce, plaintext)
-        .map_err(|e| e.to_string())?;
+        .set_error(Error::CouldNotEncryptFile)?;
 
-    Ok((salt, nonce_bytes, ciphertext))
+    Ok((nonce_bytes, ciphertext))
 }
 
-pub fn decrypt(
-    password: &str,
-    salt: &[u8],
-    nonce_bytes: &[u8],
-    ciphertext: &[u8],
-) -> Result<Vec<u8>, Error> {
-    let key_bytes = derive_key(password, salt);
-    let key = Key::<Aes256Gcm>::from_slice(&key_bytes);
-    let cipher = Aes256Gcm::new(key);
+pub fn decrypt(key: &[u8; 32], nonce_bytes: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
 
     let nonce = Nonce::from_slice(nonce_bytes);
 
@@ -85,15 +87,206 @@ pub type Env = HashMap<String, String>;
 pub struct Capsule {
     /// The version of the capsule
     pub version: Version,
-    /// Global environment variables
+    /// Global environment variables, values can reference other vars with
+    /// `${NAME}` or `${NAME:-default}`
     pub env: Option<Env>,
+    /// Host env vars passed to every process, names or prefixes ending with `*`.
+    /// Processes inherit the whole host env when unset
+    pub env_passthrough: Option<Vec<String>>,
+    /// Secrets given to the processes listing them in `use_secrets`, sealed on
+    /// their own even without a payload password and never written to disk
+    pub secrets: Option<HashMap<String, Secret>>,
+    /// Secrets once sealed, filled in by the compiler
     #[cfg_attr(test, schemars(skip))]
-    pub fs: Option<Vec<u8>>,
+    pub sealed_secrets: Option<SealedSecrets>,
+    /// Content hash -> location in the payload, filled in by the compiler
+    #[cfg_attr(test, schemars(skip))]
+    pub blobs: Option<HashMap<String, Blob>>,
     /// Global files
-    /// source -> target
-    pub files: Option<HashMap<String, String>>,
+    /// source -> target, the source is a file, a directory or a glob pattern
+    pub files: Option<HashMap<String, FileTarget>>,
+    /// Glob patterns left out of directories and glob patterns in every files mapping
+    pub exclude: Option<Vec<String>>,
+    /// Global files once embedded, filled in by the compiler
+    #[cfg_attr(test, schemars(skip))]
+    pub embedded: Option<Vec<EmbeddedFile>>,
     /// Processes to spawn
     pub processes: Option<HashMap<String, Process>>,
+    /// Where files are extracted and state is kept, relative paths are relative
+    /// to the executable. Overridden by `--data-dir` and `CAPSULE_DATA_DIR`,
+    /// defaults to `$XDG_STATE_HOME/<executable name>`, `/var/lib/<executable name>` as root
+    pub data_dir: Option<String>,
+    /// Overrides of the instances started with `--instance <name>`
+    pub instances: Option<HashMap<String, Instance>>,
+    /// How the capsule was built, filled in by the compiler
+    #[cfg_attr(test, schemars(skip))]
+    pub build: Option<BuildInfo>,
+}
+
+/// Name of the instance started without `--instance`
+pub const DEFAULT_INSTANCE: &str = "default";
+
+impl Capsule {
+    /// Applies the env overlays of instance `name`, from lowest to highest precedence:
+    /// global env, process env, instance env, instance process env
+    pub fn apply_instance(&mut self, name: &str) {
+        let Some(instance) = self.instances.as_ref().and_then(|i| i.get(name)).cloned() else {
+            return;
+        };
+        for (process_name, process) in self.processes.iter_mut().flatten() {
+            let overlay = instance
+                .processes
+                .as_ref()
+                .and_then(|p| p.get(process_name))
+                .and_then(|p| p.env.clone());
+            if instance.env.is_none() && overlay.is_none() {
+                continue;
+            }
+            let env = process.env.get_or_insert_with(Env::new);
+            env.extend(instance.env.clone().into_iter().flatten());
+            env.extend(overlay.into_iter().flatten());
+        }
+    }
+}
+
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Instance {
+    /// Env vars overriding the ones of every process
+    pub env: Option<Env>,
+    /// Process name -> overrides of this process only
+    pub processes: Option<HashMap<String, InstanceProcess>>,
+}
+
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct InstanceProcess {
+    /// Env vars overriding the instance ones
+    pub env: Option<Env>,
+}
+
+/// Instance names are used as directory names
+pub fn check_instance_name(name: &str) -> Result<(), Error> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    match valid {
+        true => Ok(()),
+        false => Err(Error::InvalidInstanceName(name.to_string())),
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BuildInfo {
+    pub target: String,
+    pub runtime_version: String,
+}
+
+/// A file of the payload
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EmbeddedFile {
+    /// Path relative to the extraction root
+    pub target: String,
+    /// Content hash, key of `Capsule::blobs`, None for symlinks
+    pub blob: Option<String>,
+    /// Path the symlink points to, relative to the link
+    pub symlink: Option<String>,
+    /// Unix permission bits
+    pub mode: Option<u32>,
+    pub mtime: Option<SystemTime>,
+}
+
+/// Where a bundled source is extracted, either a path or a path with a mode
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum FileTarget {
+    Path(String),
+    Detailed {
+        target: String,
+        /// Overrides the permissions of the source, e.g. "0755"
+        mode: Option<FileMode>,
+    },
+}
+
+/// Hand written so the error of an invalid `mode` is not replaced by the
+/// generic untagged enum message
+impl<'de> Deserialize<'de> for FileTarget {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Detailed {
+            target: String,
+            mode: Option<FileMode>,
+        }
+
+        struct TargetVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for TargetVisitor {
+            type Value = FileTarget;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a target path or an object with a target and a mode")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(FileTarget::Path(v.to_string()))
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                map: A,
+            ) -> Result<Self::Value, A::Error> {
+                let Detailed { target, mode } =
+                    Detailed::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+                Ok(FileTarget::Detailed { target, mode })
+            }
+        }
+
+        deserializer.deserialize_any(TargetVisitor)
+    }
+}
+
+impl FileTarget {
+    pub fn path(&self) -> &str {
+        match self {
+            FileTarget::Path(target) | FileTarget::Detailed { target, .. } => target,
+        }
+    }
+
+    pub fn mode(&self) -> Option<u32> {
+        match self {
+            FileTarget::Path(_) => None,
+            FileTarget::Detailed { mode, .. } => mode.as_ref().map(|m| m.0),
+        }
+    }
+}
+
+/// Unix permissions written as an octal string, validated when the capsule is parsed
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct FileMode(#[cfg_attr(test, schemars(with = "String"))] pub u32);
+
+impl TryFrom<String> for FileMode {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match u32::from_str_radix(value.trim_start_matches("0o"), 8) {
+            Ok(mode) if mode <= 0o7777 => Ok(FileMode(mode)),
+            _ => Err(format!(
+                "invalid file mode {:?}, expected an octal string like \"0755\"",
+                value
+            )),
+        }
+    }
+}
+
+impl From<FileMode> for String {
+    fn from(value: FileMode) -> Self {
+        format!("{:04o}", value.0)
+    }
 }
 
 #[cfg_attr(test, derive(schemars::JsonSchema))]
@@ -105,6 +298,16 @@ pub enum RestartPolicy {
     OnFailure,
 }
 
+impl Display for RestartPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RestartPolicy::Never => write!(f, "never"),
+            RestartPolicy::Always => write!(f, "always"),
+            RestartPolicy::OnFailure => write!(f, "on_failure"),
+        }
+    }
+}
+
 #[cfg_attr(test, derive(schemars::JsonSchema))]
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Process {
@@ -114,34 +317,443 @@ pub struct Process {
     pub args: Option<Vec<String>>,
     /// Process working directory
     pub cwd: Option<String>,
-    /// Env vars
+    /// Env vars, override the global ones
     pub env: Option<Env>,
+    /// Host env vars passed to this process, on top of the global ones
+    pub env_passthrough: Option<Vec<String>>,
+    /// Secrets of this process only, override the global ones
+    pub secrets: Option<HashMap<String, Secret>>,
+    /// Global secrets given to this process
+    pub use_secrets: Option<Vec<String>>,
     /// Restart policy
     pub restart_policy: Option<RestartPolicy>,
     /// Time in ms to wait before restarting the process
     pub restart_delay: Option<u64>,
+    /// Exponential backoff between consecutive restarts, overrides restart_delay
+    pub restart_backoff: Option<Backoff>,
+    /// Restarts allowed within restart_window before the process is marked as crash looping
+    pub max_restarts: Option<u32>,
+    /// Time in ms of the sliding window max_restarts is counted in,
+    /// counts every restart when unset
+    pub restart_window: Option<u64>,
     /// Files to embed
-    /// source -> target
-    pub files: Option<HashMap<String, String>>,
+    /// source -> target, the source is a file, a directory or a glob pattern
+    pub files: Option<HashMap<String, FileTarget>>,
+    /// Glob patterns left out of directories and glob patterns, on top of the global ones
+    pub exclude: Option<Vec<String>>,
+    /// Files once embedded, filled in by the compiler
+    #[cfg_attr(test, schemars(skip))]
+    pub embedded: Option<Vec<EmbeddedFile>>,
+    /// stdout/stderr log rotation
+    pub logs: Option<LogConfig>,
+    /// Processes that must reach a condition before this one starts
+    /// name -> condition
+    pub depends_on: Option<HashMap<String, DependencyCondition>>,
+    /// Probe used to tell if the process is healthy
+    pub health_check: Option<HealthCheck>,
+    /// Signal sent to stop the process, defaults to SIGTERM
+    pub stop_signal: Option<StopSignal>,
+    /// Time in ms to wait after the stop signal before sending SIGKILL
+    pub stop_timeout_ms: Option<u64>,
+    /// Runs the process as a one-shot job on a timer, restart_policy is ignored
+    pub schedule: Option<Schedule>,
+    /// Start a scheduled run even if the previous one is still going
+    pub allow_overlap: Option<bool>,
+}
+
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Schedule {
+    /// Cron expression in local time, e.g. "0 3 * * *", seconds are optional
+    Cron(CronExpr),
+    /// Fixed interval, e.g. "30s", "5m" or "1h 30m"
+    Every(Interval),
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Schedule::Cron(expr) => write!(f, "cron {:?}", expr.0),
+            Schedule::Every(interval) => {
+                write!(f, "every {}", humantime::format_duration(interval.0))
+            }
+        }
+    }
+}
+
+impl Schedule {
+    /// Time of the first run strictly after `after`
+    pub fn next_run(&self, after: SystemTime) -> Option<SystemTime> {
+        match self {
+            Schedule::Cron(expr) => expr
+                .parse()
+                .ok()?
+                .find_next_occurrence(&DateTime::<Local>::from(after), false)
+                .ok()
+                .map(SystemTime::from),
+            Schedule::Every(interval) => after.checked_add(interval.0),
+        }
+    }
+}
+
+/// Cron expression, validated when the capsule is parsed
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct CronExpr(String);
+
+impl CronExpr {
+    fn parse(&self) -> Result<Cron, Error> {
+        Cron::new(&self.0)
+            .with_seconds_optional()
+            .parse()
+            .map_err(|e| Error::InvalidSchedule(self.0.clone(), e.to_string()))
+    }
+}
+
+impl TryFrom<String> for CronExpr {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let expr = CronExpr(value);
+        expr.parse()?;
+        Ok(expr)
+    }
+}
+
+impl From<CronExpr> for String {
+    fn from(value: CronExpr) -> Self {
+        value.0
+    }
+}
+
+/// Human readable duration, validated when the capsule is parsed
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Interval(#[cfg_attr(test, schemars(with = "String"))] pub Duration);
+
+impl TryFrom<String> for Interval {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match humantime::parse_duration(&value) {
+            Ok(d) if !d.is_zero() => Ok(Interval(d)),
+            Ok(_) => Err(Error::InvalidSchedule(
+                value,
+                "interval must be greater than 0".to_string(),
+            )),
+            Err(e) => Err(Error::InvalidSchedule(value, e.to_string())),
+        }
+    }
+}
+
+impl From<Interval> for String {
+    fn from(value: Interval) -> Self {
+        humantime::format_duration(value.0).to_string()
+    }
+}
+
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum StopSignal {
+    Sigterm,
+    Sigint,
+    Sigquit,
+    Sighup,
+    Sigusr1,
+    Sigusr2,
+    Sigkill,
+}
+
+/// How a process was stopped
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StopOutcome {
+    /// Exited before the stop timeout
+    Graceful,
+    /// Still running after the stop timeout and got SIGKILL
+    Killed,
+    NotRunning,
+}
+
+impl Display for StopOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StopOutcome::Graceful => write!(f, "stopped gracefully"),
+            StopOutcome::Killed => write!(f, "killed after stop timeout"),
+            StopOutcome::NotRunning => write!(f, "was not running"),
+        }
+    }
+}
+
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HealthCheck {
+    /// What to probe
+    pub probe: Probe,
+    /// Time in ms between two probes
+    pub interval: Option<u64>,
+    /// Time in ms after which a probe is considered failed
+    pub timeout: Option<u64>,
+    /// Consecutive failures before the process is marked unhealthy
+    pub retries: Option<u32>,
+    /// Time in ms after start during which failures are not counted
+    pub start_period: Option<u64>,
+    /// Restart the process once it is marked unhealthy
+    pub restart_on_unhealthy: Option<bool>,
+}
+
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Probe {
+    /// Runs a command in the process cwd, healthy when it exits with 0
+    Exec {
+        cmd: String,
+        args: Option<Vec<String>>,
+    },
+    /// Healthy when a TCP connection to localhost:port succeeds
+    Tcp { port: u16 },
+    /// Healthy when GET http://localhost:port/path answers with a 2xx or 3xx
+    Http { port: u16, path: Option<String> },
+}
+
+impl Display for Probe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Probe::Exec { cmd, args } => {
+                write!(f, "exec {}", cmd)?;
+                for arg in args.iter().flatten() {
+                    write!(f, " {}", arg)?;
+                }
+                Ok(())
+            }
+            Probe::Tcp { port } => write!(f, "tcp localhost:{}", port),
+            Probe::Http { port, path } => write!(
+                f,
+                "http localhost:{}{}",
+                port,
+                path.as_deref().unwrap_or("/")
+            ),
+        }
+    }
+}
+
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DependencyCondition {
+    /// The dependency has been spawned
+    Started,
+    /// The dependency is running and healthy
+    Healthy,
+    /// The dependency exited with code 0
+    CompletedSuccessfully,
+}
+
+/// Segments of `path` once `.` and `..` are resolved, None when it is absolute,
+/// has a Windows drive or UNC prefix, or goes above the directory it is joined to.
+/// Both separators are handled whatever the platform the capsule is built or run on
+fn normalize(path: &str) -> Option<Vec<&str>> {
+    if has_root(path) {
+        return None;
+    }
+    let mut segments = vec![];
+    for segment in path.split(['/', '\\']) {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            s => segments.push(s),
+        }
+    }
+    Some(segments)
+}
+
+/// `/x`, `\\server\share`, `C:\x` or `C:x`
+fn has_root(path: &str) -> bool {
+    path.starts_with(['/', '\\']) || path.as_bytes().get(1) == Some(&b':')
+}
+
+/// Validates a file target or a process cwd and returns it with forward slashes
+pub fn safe_relative_path(path: &str) -> Result<String, Error> {
+    match normalize(path) {
+        Some(segments) if !segments.is_empty() => Ok(segments.join("/")),
+        _ => Err(Error::UnsafePath(path.to_string())),
+    }
+}
+
+/// Process names are used as the default cwd and as the name of the log
+/// directory, so they must be a single path segment
+pub fn check_process_name(name: &str) -> Result<(), Error> {
+    let valid = !has_root(name)
+        && !name.contains(['/', '\\', '\0'])
+        && normalize(name).is_some_and(|segments| segments == [name]);
+    match valid {
+        true => Ok(()),
+        false => Err(Error::InvalidProcessName(name.to_string())),
+    }
+}
+
+/// Validates that a symlink at `target` pointing to `link` stays inside the capsule directory.
+/// The check is lexical, it holds as long as no parent of `target` is a symlink
+pub fn check_symlink(target: &str, link: &str) -> Result<(), Error> {
+    let parent = normalize(target)
+        .and_then(|mut segments| segments.pop().map(|_| segments))
+        .ok_or(Error::UnsafePath(target.to_string()))?;
+    let resolved = match parent.is_empty() {
+        true => link.to_string(),
+        false => format!("{}/{}", parent.join("/"), link),
+    };
+    match has_root(link) || normalize(&resolved).is_none() {
+        true => Err(Error::UnsafePath(link.to_string())),
+        false => Ok(()),
+    }
+}
+
+/// Orders the processes so that each one comes after its dependencies,
+/// ties are broken by name so the order is stable
+pub fn start_order(processes: &HashMap<String, Process>) -> Result<Vec<String>, Error> {
+    let mut pending: BTreeMap<&String, BTreeSet<&String>> = BTreeMap::new();
+    for (name, process) in processes {
+        let mut deps = BTreeSet::new();
+        for dep in process.depends_on.iter().flat_map(|d| d.keys()) {
+            if !processes.contains_key(dep) {
+                return Err(Error::UnknownDependency(name.clone(), dep.clone()));
+            }
+            deps.insert(dep);
+        }
+        pending.insert(name, deps);
+    }
+
+    let mut order = Vec::with_capacity(pending.len());
+    while !pending.is_empty() {
+        let ready: Vec<&String> = pending
+            .iter()
+            .filter(|(_, deps)| deps.is_empty())
+            .map(|(name, _)| *name)
+            .collect();
+        if ready.is_empty() {
+            return Err(Error::DependencyCycle(
+                pending.keys().map(|n| n.to_string()).collect(),
+            ));
+        }
+        for name in ready {
+            pending.remove(name);
+            for deps in pending.values_mut() {
+                deps.remove(name);
+            }
+            order.push(name.clone());
+        }
+    }
+    Ok(order)
+}
+
+impl Process {
+    /// Delay before the restart following `attempt` consecutive restarts
+    pub fn next_restart_delay(&self, attempt: u32) -> Duration {
+        let Some(backoff) = &self.restart_backoff else {
+            return Duration::from_millis(self.restart_delay.unwrap_or(DEFAULT_RESTART_DELAY));
+        };
+        let initial = backoff
+            .initial
+            .or(self.restart_delay)
+            .unwrap_or(DEFAULT_BACKOFF_INITIAL) as f64;
+        let max = backoff.max.unwrap_or(DEFAULT_BACKOFF_MAX) as f64;
+        let multiplier = backoff.multiplier.unwrap_or(DEFAULT_BACKOFF_MULTIPLIER);
+        let delay = (initial * multiplier.powi(attempt.min(64) as i32)).min(max);
+        let jitter = backoff.jitter.unwrap_or(0.0).clamp(0.0, 1.0);
+        let delay = if jitter > 0.0 {
+            delay * (1.0 + rand::rng().random_range(-jitter..=jitter))
+        } else {
+            delay
+        };
+        Duration::from_millis(delay.max(0.0) as u64)
+    }
+}
+
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Backoff {
+    /// Delay in ms before the first restart, defaults to restart_delay or 1000
+    pub initial: Option<u64>,
+    /// Upper bound in ms of the delay, defaults to 60000
+    pub max: Option<u64>,
+    /// Factor applied to the delay after each consecutive restart, defaults to 2
+    pub multiplier: Option<f64>,
+    /// Randomizes the delay by +/- this fraction, between 0 and 1
+    pub jitter: Option<f64>,
+}
+
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LogConfig {
+    /// Max size in bytes of a log file before it is rotated
+    pub max_size: Option<u64>,
+    /// Number of rotated files to keep
+    pub max_files: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
 pub enum Status {
+    /// Waiting for dependencies
+    Waiting,
     Starting,
     /// pid
     Running(u32),
+    /// pid
+    Unhealthy(u32),
     // Exit Code
     Exited(i32),
     Killed,
+    /// Restarted more than max_restarts times within restart_window
+    CrashLooping,
+    /// Waiting for the first scheduled run
+    Scheduled,
+    /// Stopped with `proc stop`, not restarted until `proc start`
+    Stopped,
+}
+
+impl Status {
+    /// Stable name used by the machine readable output
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Waiting => "waiting",
+            Status::Starting => "starting",
+            Status::Running(_) => "running",
+            Status::Unhealthy(_) => "unhealthy",
+            Status::Exited(_) => "exited",
+            Status::Killed => "killed",
+            Status::CrashLooping => "crash_looping",
+            Status::Scheduled => "scheduled",
+            Status::Stopped => "stopped",
+        }
+    }
+
+    pub fn pid(&self) -> Option<u32> {
+        match self {
+            Status::Running(pid) | Status::Unhealthy(pid) => Some(*pid),
+            _ => None,
+        }
+    }
 }
 
 impl Display for Status {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            Status::Waiting => write!(f, "Waiting"),
             Status::Starting => write!(f, "starting"),
             Status::Running(pid) => write!(f, "Running pid {}", pid),
+            Status::Unhealthy(pid) => write!(f, "Unhealthy pid {}", pid),
             Status::Exited(code) => write!(f, "Exited code {}", code),
             Status::Killed => write!(f, "Killed"),
+            Status::CrashLooping => write!(f, "Crash looping"),
+            Status::Scheduled => write!(f, "Scheduled"),
+            Status::Stopped => write!(f, "Stopped"),
         }
     }
 }
@@ -150,16 +762,72 @@ pub struct RunningProcess {
     pub name: String,
     pub status: Status,
     pub config: Process,
-    pub child: Child,
+    /// None until the process is first started
+    pub child: Option<Child>,
     pub started: Instant,
     pub force_restart: bool,
     pub restarts: u32,
+    pub health: HealthState,
+    /// Start time of the recent restarts, used for max_restarts
+    pub restart_history: VecDeque<Instant>,
+    /// Consecutive restarts used for the backoff, reset once the process stays up
+    pub backoff_attempt: u32,
+    /// Set while waiting for the backoff delay
+    pub next_restart: Option<Instant>,
+    pub last_exit_code: Option<i32>,
+    pub last_restart: Option<SystemTime>,
+    /// Scheduled processes only
+    pub next_run: Option<SystemTime>,
+    /// Start of the most recent scheduled run
+    pub last_run: Option<SystemTime>,
+    /// Duration of the most recent finished scheduled run
+    pub last_run_duration: Option<Duration>,
+    /// Previous scheduled runs still going when allow_overlap is set
+    pub overlapping: Vec<(Child, Instant)>,
+}
+
+impl RunningProcess {
+    /// Waiting for its dependencies, nothing started yet
+    pub fn new(name: &str, config: Process) -> Self {
+        RunningProcess {
+            name: name.to_string(),
+            status: Status::Waiting,
+            config,
+            child: None,
+            started: Instant::now(),
+            force_restart: false,
+            restarts: 0,
+            health: HealthState::default(),
+            restart_history: VecDeque::new(),
+            backoff_attempt: 0,
+            next_restart: None,
+            last_exit_code: None,
+            last_restart: None,
+            next_run: None,
+            last_run: None,
+            last_run_duration: None,
+            overlapping: vec![],
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct HealthState {
+    /// Set once a probe succeeded, reset on restart
+    pub healthy: bool,
+    /// Consecutive failed probes
+    pub failures: u32,
+    pub last_probe: Option<Instant>,
+    pub in_flight: Option<JoinHandle<bool>>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub enum CliMessage {
     Kill { name: String },
     Restart { name: String },
+    Start { name: String },
+    Stop { name: String },
+    ProcStatus { name: String },
     List,
     TearDown,
     KillAll,
@@ -167,12 +835,140 @@ pub enum CliMessage {
     KillDaemon,
 }
 
+/// Frame sent by the CLI, the token is read from the file written by the supervisor
+#[derive(Serialize, Deserialize)]
+pub struct CliRequest {
+    pub token: Vec<u8>,
+    pub message: CliMessage,
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum SupervisorResp {
     Ok,
     Error(Error),
     List(Vec<ListResp>),
     Version(Version),
+    /// name -> how it was stopped, in stop order
+    Stopped(Vec<(String, StopOutcome)>),
+    ProcStatus(Box<ProcStatusResp>),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ProcStatusResp {
+    pub name: String,
+    pub status: Status,
+    pub pid: Option<u32>,
+    /// Config of the process, env values are left out
+    pub config: Process,
+    /// Env vars set on the process, global ones included
+    pub env_keys: Vec<String>,
+    /// Secrets given to the process, values are never included
+    pub secret_keys: Vec<String>,
+    pub cwd: String,
+    pub restarts: u32,
+    pub last_exit_code: Option<i32>,
+    /// Unix timestamp in seconds
+    pub last_restart: Option<u64>,
+    /// Seconds since the current run started
+    pub uptime: Option<u64>,
+    /// Unix timestamp in seconds
+    pub next_run: Option<u64>,
+}
+
+fn ago(timestamp: u64) -> String {
+    let at = SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp);
+    let elapsed = SystemTime::now().duration_since(at).unwrap_or_default();
+    format!("{} ago", elapsed.human(humanize_duration::Truncate::Second))
+}
+
+impl Display for ProcStatusResp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let dash = || "-".to_string();
+        let config = &self.config;
+        let mut command = vec![config.cmd.clone()];
+        command.extend(config.args.iter().flatten().cloned());
+        let mut depends_on: Vec<_> = config
+            .depends_on
+            .iter()
+            .flatten()
+            .map(|(name, condition)| format!("{} ({:?})", name, condition))
+            .collect();
+        depends_on.sort();
+
+        let rows = [
+            ("Name", self.name.clone()),
+            ("Status", self.status.to_string()),
+            ("Pid", self.pid.map(|p| p.to_string()).unwrap_or_else(dash)),
+            ("Command", command.join(" ")),
+            ("Cwd", self.cwd.clone()),
+            ("Env", self.env_keys.join(", ")),
+            (
+                "Secrets",
+                match self.secret_keys.is_empty() {
+                    true => dash(),
+                    false => self.secret_keys.join(", "),
+                },
+            ),
+            (
+                "Uptime",
+                self.uptime
+                    .map(|s| Duration::from_secs(s).human(humanize_duration::Truncate::Second))
+                    .map(|d| d.to_string())
+                    .unwrap_or_else(dash),
+            ),
+            (
+                "Restart policy",
+                config
+                    .restart_policy
+                    .as_ref()
+                    .unwrap_or(&RestartPolicy::Never)
+                    .to_string(),
+            ),
+            ("Restarts", self.restarts.to_string()),
+            (
+                "Last exit",
+                self.last_exit_code
+                    .map(|c| c.to_string())
+                    .unwrap_or_else(dash),
+            ),
+            (
+                "Last restart",
+                self.last_restart.map(ago).unwrap_or_else(dash),
+            ),
+            ("Depends on", depends_on.join(", ")),
+            (
+                "Health check",
+                match &config.health_check {
+                    Some(HealthCheck { probe, .. }) => probe.to_string(),
+                    None => dash(),
+                },
+            ),
+            (
+                "Schedule",
+                config
+                    .schedule
+                    .as_ref()
+                    .map(|s| s.to_string())
+                    .unwrap_or_else(dash),
+            ),
+            (
+                "Next run",
+                self.next_run
+                    .map(|t| {
+                        let at = SystemTime::UNIX_EPOCH + Duration::from_secs(t);
+                        let wait = at.duration_since(SystemTime::now()).unwrap_or_default();
+                        format!("in {}", wait.human(humanize_duration::Truncate::Second))
+                    })
+                    .unwrap_or_else(dash),
+            ),
+        ];
+        let width = rows.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+        for (key, value) in rows {
+            let value = if value.is_empty() { dash() } else { value };
+            writeln!(f, "{:width$}: {}", key, value, width = width)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -184,6 +980,15 @@ pub struct ListResp {
     pub disk_usage: (u64, u64),
     pub restarts: u32,
     pub run_time: u64,
+    pub last_exit_code: Option<i32>,
+    /// Unix timestamp in seconds
+    pub last_restart: Option<u64>,
+    /// Unix timestamp in seconds
+    pub last_run: Option<u64>,
+    /// Milliseconds
+    pub last_run_duration: Option<u64>,
+    /// Unix timestamp in seconds
+    pub next_run: Option<u64>,
 }
 
 #[derive(Error, Debug, Serialize, Deserialize, Clone)]
@@ -192,14 +997,18 @@ pub enum Error {
     ProcessNotFound(String),
     #[error("Supervisor can't be found")]
     SupervisorCantBeFound,
-    #[error("Could not start UDP server")]
-    CouldNotStartUdpServer,
+    #[error("Could not start the control socket")]
+    CouldNotStartControlSocket,
+    #[error("Unauthorized")]
+    Unauthorized,
     #[error("No data provided")]
     NoData,
     #[error("Invalid password")]
     InvalidPassword,
     #[error("Invalid data format")]
     InvalidDataFormat,
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
     #[error("Could not find file {0:?}")]
     CouldNotFindFile(String),
 
@@ -226,6 +1035,57 @@ pub enum Error {
 
     #[error("Unsupported target")]
     UnsupportedTarget(String),
+
+    #[error("Process {0:?} depends on unknown process {1:?}")]
+    UnknownDependency(String, String),
+
+    #[error("Dependency cycle between {0:?}")]
+    DependencyCycle(Vec<String>),
+
+    #[error("Invalid schedule {0:?}: {1}")]
+    InvalidSchedule(String, String),
+
+    #[error("Process {0:?} is already running")]
+    ProcessAlreadyRunning(String),
+
+    #[error("{0} is only available with --output text")]
+    UnsupportedOutput(String),
+
+    #[error("Invalid glob pattern {0:?}")]
+    InvalidPattern(String),
+
+    #[error("No files match {0:?}")]
+    NoFilesMatched(String),
+
+    #[error("Several files are mapped to {0:?}")]
+    DuplicateTarget(String),
+
+    #[error("Path {0:?} must be relative and stay inside the capsule directory")]
+    UnsafePath(String),
+
+    #[error("Invalid instance name {0:?}, use letters, digits, - and _")]
+    InvalidInstanceName(String),
+
+    #[error("Invalid process name {0:?}, it must be a single path segment")]
+    InvalidProcessName(String),
+
+    #[error("Invalid env var {0}: {1}")]
+    InvalidEnv(String, String),
+
+    #[error("Unsupported payload format {0}, built by a newer compiler")]
+    UnsupportedFormat(u16),
+
+    #[error("Integrity check failed, the capsule is truncated or was modified")]
+    IntegrityCheckFailed,
+
+    #[error("Signature check failed: {0}")]
+    SignatureCheckFailed(String),
+
+    #[error("File secrets need a tmpfs, set XDG_RUNTIME_DIR or mount /dev/shm")]
+    NoTmpfs,
+
+    #[error("{0:?} was not created by this capsule, refusing to replace or remove it")]
+    NotOwned(String),
 }
 
 impl<T> Exitable<T> for Result<T, Error> {
@@ -281,9 +1141,35 @@ pub trait ExitableError<T> {
 }
 
 impl Error {
+    /// Exit code of the CLIs, documented in the Readme
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::UnsupportedOutput(_) | Error::InvalidInstanceName(_) => 2,
+            Error::SupervisorCantBeFound => 3,
+            Error::ProcessNotFound(_) => 4,
+            Error::ProcessAlreadyRunning(_) => 5,
+            Error::InvalidPassword | Error::Unauthorized => 6,
+            Error::NoData
+            | Error::InvalidDataFormat
+            | Error::UnsupportedFormat(_)
+            | Error::IntegrityCheckFailed
+            | Error::InvalidConfig(_)
+            | Error::InvalidSchedule(_, _)
+            | Error::UnknownDependency(_, _)
+            | Error::DependencyCycle(_)
+            | Error::InvalidPattern(_)
+            | Error::NoFilesMatched(_)
+            | Error::DuplicateTarget(_)
+            | Error::UnsafePath(_)
+            | Error::InvalidProcessName(_)
+            | Error::InvalidEnv(_, _) => 7,
+            Error::SignatureCheckFailed(_) => 8,
+            _ => 1,
+        }
+    }
     pub fn exit(self) -> ! {
         eprintln!("Error: {}", self.to_string());
-        process::exit(1);
+        process::exit(self.exit_code());
     }
     pub fn log(&self) {
         eprintln!("Error: {}", self.to_string())
@@ -309,6 +1195,10 @@ impl Display for Table {
             "IO Writes".to_string(),
             "Run time".to_string(),
             "Restarts".to_string(),
+            "Last exit".to_string(),
+            "Last restart".to_string(),
+            "Last run".to_string(),
+            "Next run".to_string(),
         ];
         let mut max_size: Vec<_> = head.iter().map(|s| s.len()).collect();
 
@@ -323,6 +1213,44 @@ impl Display for Table {
             let disk_read = ByteSize::b(d.disk_usage.0);
             let disk_write = ByteSize::b(d.disk_usage.1);
             let restarts = d.restarts;
+            let last_exit = d
+                .last_exit_code
+                .map(|c| c.to_string())
+                .unwrap_or("-".to_string());
+            let last_restart = d
+                .last_restart
+                .and_then(|t| {
+                    let restarted = SystemTime::UNIX_EPOCH + Duration::from_secs(t);
+                    SystemTime::now().duration_since(restarted).ok()
+                })
+                .map(|d| format!("{} ago", d.human(humanize_duration::Truncate::Second)))
+                .unwrap_or("-".to_string());
+            let last_run = d
+                .last_run
+                .and_then(|t| {
+                    let started = SystemTime::UNIX_EPOCH + Duration::from_secs(t);
+                    SystemTime::now().duration_since(started).ok()
+                })
+                .map(|ago| {
+                    let ago = ago.human(humanize_duration::Truncate::Second);
+                    match d.last_run_duration {
+                        Some(ms) => format!(
+                            "{} ago, took {}",
+                            ago,
+                            Duration::from_millis(ms).human(humanize_duration::Truncate::Millis)
+                        ),
+                        None => format!("{} ago", ago),
+                    }
+                })
+                .unwrap_or("-".to_string());
+            let next_run = d
+                .next_run
+                .map(|t| {
+                    let at = SystemTime::UNIX_EPOCH + Duration::from_secs(t);
+                    let wait = at.duration_since(SystemTime::now()).unwrap_or_default();
+                    format!("in {}", wait.human(humanize_duration::Truncate::Second))
+                })
+                .unwrap_or("-".to_string());
             let line = vec![
                 format!("{}", name),
                 format!("{}", status),
@@ -332,6 +1260,10 @@ impl Display for Table {
                 format!("{}", disk_write),
                 format!("{}", runtime),
                 format!("{}", restarts),
+                last_exit,
+                last_restart,
+                last_run,
+                next_run,
             ];
             for (i, c) in line.iter().enumerate() {
                 max_size[i] = max_size[i].max(c.len())
@@ -402,10 +1334,15 @@ impl Display for Table {
 
 #[cfg(test)]
 mod test {
-    use crate::Capsule;
+    use crate::{
+        Capsule, DependencyCondition, Error, FileTarget, Process, Schedule, check_instance_name,
+        check_process_name, check_symlink, safe_relative_path, start_order,
+    };
+    use std::collections::HashMap;
     use std::env;
     use std::fs;
     use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
     const SCHEMAS_FOLDER: &str = "schemas";
     const SCHEMA_NAME: &str = "capsule.json";
 
@@ -419,4 +1356,190 @@ mod test {
             .join(SCHEMAS_FOLDER);
         fs::write(manifest_dir.join(SCHEMA_NAME), json).unwrap();
     }
+
+    fn process(deps: &[&str]) -> Process {
+        serde_json::from_value(serde_json::json!({
+            "cmd": "true",
+            "depends_on": deps
+                .iter()
+                .map(|d| (d.to_string(), DependencyCondition::Started))
+                .collect::<HashMap<_, _>>(),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn start_order_follows_dependencies() {
+        let processes = HashMap::from([
+            ("worker".to_string(), process(&["api"])),
+            ("api".to_string(), process(&["migrate"])),
+            ("migrate".to_string(), process(&[])),
+            ("cron".to_string(), process(&[])),
+        ]);
+        let order = start_order(&processes).unwrap();
+        assert_eq!(order, vec!["cron", "migrate", "api", "worker"]);
+    }
+
+    #[test]
+    fn start_order_rejects_cycles() {
+        let processes = HashMap::from([
+            ("a".to_string(), process(&["b"])),
+            ("b".to_string(), process(&["a"])),
+            ("c".to_string(), process(&[])),
+        ]);
+        match start_order(&processes) {
+            Err(Error::DependencyCycle(names)) => assert_eq!(names, vec!["a", "b"]),
+            _ => panic!("expected a dependency cycle"),
+        }
+    }
+
+    #[test]
+    fn start_order_rejects_unknown_dependencies() {
+        let processes = HashMap::from([("a".to_string(), process(&["missing"]))]);
+        assert!(matches!(
+            start_order(&processes),
+            Err(Error::UnknownDependency(_, _))
+        ));
+    }
+
+    #[test]
+    fn restart_backoff_grows_up_to_max() {
+        let process: Process = serde_json::from_value(serde_json::json!({
+            "cmd": "true",
+            "restart_backoff": { "initial": 100, "max": 1000, "multiplier": 3.0 },
+        }))
+        .unwrap();
+        let delays: Vec<_> = (0..4)
+            .map(|n| process.next_restart_delay(n).as_millis())
+            .collect();
+        assert_eq!(delays, vec![100, 300, 900, 1000]);
+    }
+
+    #[test]
+    fn schedule_every_adds_interval() {
+        let schedule: Schedule = serde_json::from_str(r#"{"every": "1m 30s"}"#).unwrap();
+        let now = SystemTime::now();
+        assert_eq!(schedule.next_run(now), Some(now + Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn schedule_cron_finds_next_minute() {
+        let schedule: Schedule = serde_json::from_str(r#"{"cron": "* * * * *"}"#).unwrap();
+        let now = SystemTime::now();
+        let next = schedule.next_run(now).unwrap();
+        let wait = next.duration_since(now).unwrap();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn schedule_is_validated_on_parse() {
+        assert!(serde_json::from_str::<Schedule>(r#"{"cron": "61 * * * *"}"#).is_err());
+        assert!(serde_json::from_str::<Schedule>(r#"{"every": "soon"}"#).is_err());
+        assert!(serde_json::from_str::<Schedule>(r#"{"every": "0s"}"#).is_err());
+    }
+
+    #[test]
+    fn safe_relative_path_stays_inside() {
+        assert_eq!(safe_relative_path("bin/setup.sh").unwrap(), "bin/setup.sh");
+        assert_eq!(safe_relative_path("./a//b/").unwrap(), "a/b");
+        assert_eq!(safe_relative_path("a\\b").unwrap(), "a/b");
+        assert_eq!(safe_relative_path("a/../b").unwrap(), "b");
+
+        for path in [
+            "",
+            ".",
+            "a/..",
+            "../x",
+            "a/../../x",
+            "../../etc/cron.d/x",
+            "..\\x",
+            "/etc/passwd",
+            "\\etc",
+            "C:\\Windows\\x",
+            "C:/x",
+            "C:x",
+            "\\\\server\\share\\x",
+            "//server/share/x",
+        ] {
+            assert!(
+                matches!(safe_relative_path(path), Err(Error::UnsafePath(_))),
+                "{path:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn process_names_are_single_segments() {
+        assert!(check_process_name("api").is_ok());
+        assert!(check_process_name("web.v2").is_ok());
+        for name in ["", ".", "..", "a/b", "../x", "a\\b", "/x", "C:", "a\0b"] {
+            assert!(
+                matches!(check_process_name(name), Err(Error::InvalidProcessName(_))),
+                "{name:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn symlinks_stay_inside() {
+        assert!(check_symlink("lib/libx.so", "libx.so.1").is_ok());
+        assert!(check_symlink("current", "releases/v2").is_ok());
+        assert!(check_symlink("lib/current", "../shared").is_ok());
+        assert!(check_symlink("lib/root", "..").is_ok());
+        assert!(check_symlink("lib/escape", "../..").is_err());
+        assert!(check_symlink("escape", "../etc").is_err());
+        assert!(check_symlink("abs", "/etc/passwd").is_err());
+        assert!(check_symlink("drive", "C:\\Windows").is_err());
+    }
+
+    #[test]
+    fn instance_env_overrides_process_env() {
+        let mut capsule: Capsule = serde_json::from_str(
+            r#"{
+                "version": "1.0.0",
+                "env": {"LEVEL": "global"},
+                "processes": {
+                    "api": {"cmd": "api", "env": {"LEVEL": "process", "PORT": "80"}},
+                    "worker": {"cmd": "worker"}
+                },
+                "instances": {
+                    "canary": {
+                        "env": {"LEVEL": "canary"},
+                        "processes": {"api": {"env": {"PORT": "8080"}}}
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        capsule.apply_instance("staging");
+        assert_eq!(capsule.processes.as_ref().unwrap()["worker"].env, None);
+
+        capsule.apply_instance("canary");
+        let processes = capsule.processes.as_ref().unwrap();
+        let api = processes["api"].env.as_ref().unwrap();
+        assert_eq!(api["LEVEL"], "canary");
+        assert_eq!(api["PORT"], "8080");
+        assert_eq!(processes["worker"].env.as_ref().unwrap()["LEVEL"], "canary");
+        assert!(check_instance_name("canary-2").is_ok());
+        assert!(check_instance_name("../x").is_err());
+        assert!(check_instance_name("").is_err());
+    }
+
+    #[test]
+    fn file_target_is_a_path_or_has_a_mode() {
+        let files: HashMap<String, FileTarget> = serde_json::from_str(
+            r#"{"a.sh": "bin/a.sh", "b.sh": {"target": "bin/b.sh", "mode": "0755"}}"#,
+        )
+        .unwrap();
+        assert_eq!(files["a.sh"].path(), "bin/a.sh");
+        assert_eq!(files["a.sh"].mode(), None);
+        assert_eq!(files["b.sh"].path(), "bin/b.sh");
+        assert_eq!(files["b.sh"].mode(), Some(0o755));
+
+        let err = serde_json::from_str::<FileTarget>(r#"{"target": "a", "mode": "rwx"}"#)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("invalid file mode"));
+        assert!(serde_json::from_str::<FileTarget>(r#"{"target": "a", "mode": "17777"}"#).is_err());
+    }
 }
@@ -0,0 +1,796 @@
+//! Payload appended to the runtime executable.
+//!
+//! ```text
+//! runtime | blobs | settings | header | manifest | footer
+//! ```
+//!
+//! Blobs hold the file contents, stored once per content hash and read lazily by
+//! offset. The manifest is the postcard encoded `Capsule` pointing at them. With a
+//! key the manifest is sealed as a whole and each blob is sealed in chunks of
+//! `CHUNK_SIZE` (STREAM construction), so memory use does not depend on file sizes.
+//! Settings and the header are never encrypted, they hold what the CLI needs
+//! before a key is known. The header says how the key is obtained. The footer
+//! ends with a SHA-256 digest of everything before it, runtime included, so
+//! truncated or modified executables are refused even when they are plain, and
+//! the Ed25519 signature of this digest when the capsule is signed.
+
+use crate::keys::{self, KEY_SIZE, Kdf, SALT_SIZE};
+use crate::signing::{self, KEY_ID_SIZE, SIGNATURE_SIZE, Signature};
+use crate::{Capsule, Error, MAGIC_NUMBER, SetError, decrypt, encrypt};
+use aes_gcm::aead::AeadInPlace;
+use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const NONCE_PREFIX_SIZE: usize = 7;
+
+/// Version of the payload layout, runtimes refuse newer ones
+pub const FORMAT_VERSION: u16 = 1;
+
+const DIGEST_SIZE: usize = 32;
+/// payload len | settings len | header len | manifest len, covered by the digest
+const LENGTHS_SIZE: usize = 8 + 8 + 8 + 8;
+
+/// signed flag | key id | signature, zeroed when unsigned
+const SIGNED_SIZE: usize = 1 + KEY_ID_SIZE + SIGNATURE_SIZE;
+
+/// lengths | digest | signature | format version | magic
+pub const FOOTER_SIZE: usize = LENGTHS_SIZE + DIGEST_SIZE + SIGNED_SIZE + 2 + 8;
+
+/// Location of a file content in the payload
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Blob {
+    /// Offset from the start of the payload
+    pub offset: u64,
+    /// Size of the content, before encryption
+    pub size: u64,
+    /// Nonce prefix of the chunks, encrypted payloads only
+    pub nonce: Option<[u8; NONCE_PREFIX_SIZE]>,
+}
+
+/// Part of the capsule config readable without the password
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Settings {
+    /// `Capsule::data_dir`, the CLI needs it to find the supervisor
+    pub data_dir: Option<String>,
+    /// The secrets are sealed with a password of their own, `daemon start`
+    /// asks for it on top of the payload one
+    pub secrets_password: bool,
+}
+
+/// How the payload key is obtained
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Scheme {
+    Plain,
+    /// Derived from a password
+    Password {
+        kdf: Kdf,
+        salt: [u8; SALT_SIZE],
+    },
+    /// 32 bytes given as is
+    Key,
+    /// Agreed between an ephemeral key of the compiler and the X25519 key of the
+    /// host, which needs the private key
+    Recipient {
+        ephemeral: [u8; KEY_SIZE],
+        recipient: [u8; KEY_SIZE],
+    },
+}
+
+impl Scheme {
+    pub fn is_encrypted(&self) -> bool {
+        *self != Scheme::Plain
+    }
+
+    /// What the runtime asks for
+    pub fn prompt(&self) -> &'static str {
+        match self {
+            Scheme::Plain | Scheme::Password { .. } => "Enter password: ",
+            Scheme::Key => "Enter key: ",
+            Scheme::Recipient { .. } => "Enter private key: ",
+        }
+    }
+}
+
+impl Display for Scheme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scheme::Plain => write!(f, "none"),
+            Scheme::Password { kdf, .. } => write!(f, "password ({})", kdf),
+            Scheme::Key => write!(f, "key"),
+            Scheme::Recipient { recipient, .. } => write!(f, "recipient {}", hex(recipient)),
+        }
+    }
+}
+
+/// What a payload is sealed with when it is written
+pub enum Lock {
+    Password {
+        password: String,
+        kdf: Kdf,
+    },
+    Key([u8; KEY_SIZE]),
+    /// X25519 public key of the host
+    Recipient([u8; KEY_SIZE]),
+}
+
+/// Read before the manifest, postcard encoded
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct Header {
+    scheme: Scheme,
+    /// Nonce of the manifest, encrypted payloads only
+    nonce: Option<[u8; NONCE_SIZE]>,
+}
+
+struct Footer {
+    payload_len: u64,
+    settings_len: u64,
+    header_len: u64,
+    manifest_len: u64,
+    /// SHA-256 of the runtime, the payload and the lengths
+    digest: [u8; DIGEST_SIZE],
+    /// Signature of the digest and the format version
+    signature: Option<Signature>,
+    version: u16,
+}
+
+impl Footer {
+    fn lengths(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(LENGTHS_SIZE);
+        bytes.extend_from_slice(&self.payload_len.to_le_bytes());
+        bytes.extend_from_slice(&self.settings_len.to_le_bytes());
+        bytes.extend_from_slice(&self.header_len.to_le_bytes());
+        bytes.extend_from_slice(&self.manifest_len.to_le_bytes());
+        bytes
+    }
+
+    /// digest | signature | format version | magic
+    fn tail(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(FOOTER_SIZE - LENGTHS_SIZE);
+        bytes.extend_from_slice(&self.digest);
+        match &self.signature {
+            Some(signature) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&signature.key_id);
+                bytes.extend_from_slice(&signature.signature);
+            }
+            None => bytes.extend_from_slice(&[0u8; SIGNED_SIZE]),
+        }
+        bytes.extend_from_slice(&self.version.to_le_bytes());
+        bytes.extend_from_slice(MAGIC_NUMBER);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; FOOTER_SIZE]) -> Result<Self, Error> {
+        let (lengths, rest) = bytes.split_at(LENGTHS_SIZE);
+        let (digest, rest) = rest.split_at(DIGEST_SIZE);
+        let (signed, rest) = rest.split_at(SIGNED_SIZE);
+        let (version, magic) = rest.split_at(2);
+        if magic != MAGIC_NUMBER {
+            return Err(Error::NoData);
+        }
+        let version = u16::from_le_bytes(version.try_into().set_error(Error::NoData)?);
+        if version > FORMAT_VERSION {
+            return Err(Error::UnsupportedFormat(version));
+        }
+        let len = |i: usize| -> Result<u64, Error> {
+            let bytes = lengths[i * 8..i * 8 + 8].try_into();
+            Ok(u64::from_le_bytes(bytes.set_error(Error::NoData)?))
+        };
+        Ok(Footer {
+            payload_len: len(0)?,
+            settings_len: len(1)?,
+            header_len: len(2)?,
+            manifest_len: len(3)?,
+            digest: digest.try_into().set_error(Error::NoData)?,
+            signature: match signed[0] {
+                0 => None,
+                _ => Some(Signature {
+                    key_id: signed[1..1 + KEY_ID_SIZE]
+                        .try_into()
+                        .set_error(Error::NoData)?,
+                    signature: signed[1 + KEY_ID_SIZE..]
+                        .try_into()
+                        .set_error(Error::NoData)?,
+                }),
+            },
+            version,
+        })
+    }
+}
+
+/// prefix | chunk counter | last chunk flag
+fn chunk_nonce(prefix: &[u8; NONCE_PREFIX_SIZE], counter: u32, last: bool) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_SIZE..NONCE_SIZE - 1].copy_from_slice(&counter.to_be_bytes());
+    nonce[NONCE_SIZE - 1] = last as u8;
+    nonce
+}
+
+fn cipher(key: &[u8; 32]) -> Aes256Gcm {
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key))
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Passes writes through and hashes them
+struct Hashing<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes the runtime, blobs as they are added, then the manifest and the footer
+pub struct PayloadWriter<W: Write> {
+    out: Hashing<W>,
+    written: u64,
+    scheme: Scheme,
+    key: Option<[u8; KEY_SIZE]>,
+    blobs: HashMap<String, Blob>,
+}
+
+impl<W: Write> PayloadWriter<W> {
+    /// Writes `runtime` to `out`, the payload follows it
+    pub fn new(out: W, runtime: &[u8], lock: Option<&Lock>) -> Result<Self, Error> {
+        let (scheme, key) = match lock {
+            None => (Scheme::Plain, None),
+            Some(Lock::Password { password, kdf }) => {
+                let salt = keys::salt();
+                let key = kdf.derive(password, &salt)?;
+                (Scheme::Password { kdf: *kdf, salt }, Some(key))
+            }
+            Some(Lock::Key(key)) => (Scheme::Key, Some(*key)),
+            Some(Lock::Recipient(recipient)) => {
+                let (ephemeral, key) = keys::seal_for(recipient)?;
+                let recipient = *recipient;
+                (
+                    Scheme::Recipient {
+                        ephemeral,
+                        recipient,
+                    },
+                    Some(key),
+                )
+            }
+        };
+        let mut out = Hashing {
+            inner: out,
+            hasher: Sha256::new(),
+        };
+        out.write_all(runtime).set_error(Error::InternalError)?;
+        Ok(PayloadWriter {
+            out,
+            written: 0,
+            scheme,
+            key,
+            blobs: HashMap::new(),
+        })
+    }
+
+    /// Key of the payload, None when it is plain
+    pub fn key(&self) -> Option<&[u8; KEY_SIZE]> {
+        self.key.as_ref()
+    }
+
+    /// Streams `src` into the payload and returns its content hash.
+    /// `src` is read twice, identical contents are only stored once
+    pub fn add(&mut self, src: &mut (impl Read + Seek)) -> io::Result<String> {
+        let mut hasher = Sha256::new();
+        let size = io::copy(src, &mut hasher)?;
+        let hash = hex(&hasher.finalize());
+        if self.blobs.contains_key(&hash) {
+            return Ok(hash);
+        }
+        src.seek(SeekFrom::Start(0))?;
+        let blob = self.write_blob(src, size)?;
+        self.blobs.insert(hash.clone(), blob);
+        Ok(hash)
+    }
+
+    fn write_blob(&mut self, src: &mut impl Read, size: u64) -> io::Result<Blob> {
+        let offset = self.written;
+        let Some(key) = &self.key else {
+            let copied = io::copy(&mut src.take(size), &mut self.out)?;
+            if copied != size {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.written += copied;
+            return Ok(Blob {
+                offset,
+                size,
+                nonce: None,
+            });
+        };
+
+        let cipher = cipher(key);
+        let mut prefix = [0u8; NONCE_PREFIX_SIZE];
+        rand::rng().fill_bytes(&mut prefix);
+        let mut buf = Vec::with_capacity(CHUNK_SIZE + TAG_SIZE);
+        let mut remaining = size;
+        let mut counter = 0u32;
+        loop {
+            let len = remaining.min(CHUNK_SIZE as u64);
+            buf.clear();
+            if src.take(len).read_to_end(&mut buf)? as u64 != len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            remaining -= len;
+            let last = remaining == 0;
+            let nonce = chunk_nonce(&prefix, counter, last);
+            cipher
+                .encrypt_in_place(Nonce::from_slice(&nonce), b"", &mut buf)
+                .map_err(|_| io::Error::other("could not encrypt chunk"))?;
+            self.out.write_all(&buf)?;
+            self.written += buf.len() as u64;
+            if last {
+                break;
+            }
+            counter = counter
+                .checked_add(1)
+                .ok_or_else(|| io::Error::other("file too large"))?;
+        }
+        Ok(Blob {
+            offset,
+            size,
+            nonce: Some(prefix),
+        })
+    }
+
+    /// Fills `capsule.blobs`, writes the settings, the header, the manifest and
+    /// the footer, signed with `signing_key` when given
+    pub fn finish(
+        mut self,
+        mut capsule: Capsule,
+        signing_key: Option<&[u8; KEY_SIZE]>,
+    ) -> Result<W, Error> {
+        capsule.blobs = Some(self.blobs);
+        let settings = Settings {
+            data_dir: capsule.data_dir.clone(),
+            secrets_password: capsule
+                .sealed_secrets
+                .as_ref()
+                .is_some_and(|s| s.own_password()),
+        };
+        let settings = postcard::to_allocvec(&settings).set_error(Error::InternalError)?;
+        let manifest = postcard::to_allocvec(&capsule).set_error(Error::InternalError)?;
+        let (manifest, nonce) = match &self.key {
+            Some(key) => {
+                let (nonce, ciphertext) = encrypt(key, &manifest)?;
+                (ciphertext, Some(nonce))
+            }
+            None => (manifest, None),
+        };
+        let header = Header {
+            scheme: self.scheme,
+            nonce,
+        };
+        let header = postcard::to_allocvec(&header).set_error(Error::InternalError)?;
+        let mut footer = Footer {
+            payload_len: self.written
+                + settings.len() as u64
+                + header.len() as u64
+                + manifest.len() as u64,
+            settings_len: settings.len() as u64,
+            header_len: header.len() as u64,
+            manifest_len: manifest.len() as u64,
+            digest: [0u8; DIGEST_SIZE],
+            signature: None,
+            version: FORMAT_VERSION,
+        };
+        self.out
+            .write_all(&settings)
+            .and_then(|_| self.out.write_all(&header))
+            .and_then(|_| self.out.write_all(&manifest))
+            .and_then(|_| self.out.write_all(&footer.lengths()))
+            .set_error(Error::InternalError)?;
+        let Hashing { mut inner, hasher } = self.out;
+        footer.digest = hasher.finalize().into();
+        footer.signature =
+            signing_key.map(|key| signing::sign(key, footer.version, &footer.digest));
+        inner
+            .write_all(&footer.tail())
+            .and_then(|_| inner.flush())
+            .set_error(Error::InternalError)?;
+        Ok(inner)
+    }
+}
+
+/// Reads the payload at the end of `src`
+pub struct Payload<R> {
+    src: R,
+    /// Offset of the payload in `src`
+    start: u64,
+    footer: Footer,
+    header: Header,
+    key: Option<[u8; KEY_SIZE]>,
+}
+
+impl Payload<File> {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        Payload::new(File::open(path).set_error(Error::NoData)?)
+    }
+}
+
+impl<R: Read + Seek> Payload<R> {
+    pub fn new(mut src: R) -> Result<Self, Error> {
+        let end = src.seek(SeekFrom::End(0)).set_error(Error::NoData)?;
+        let footer_start = end.checked_sub(FOOTER_SIZE as u64).ok_or(Error::NoData)?;
+        src.seek(SeekFrom::Start(footer_start))
+            .set_error(Error::NoData)?;
+        let mut bytes = [0u8; FOOTER_SIZE];
+        src.read_exact(&mut bytes).set_error(Error::NoData)?;
+        let footer = Footer::from_bytes(&bytes)?;
+        let start = footer_start
+            .checked_sub(footer.payload_len)
+            .ok_or(Error::InvalidDataFormat)?;
+        // the lengths are not authenticated yet, their sums below fit once this one does
+        let footer_len = footer
+            .settings_len
+            .checked_add(footer.header_len)
+            .and_then(|len| len.checked_add(footer.manifest_len))
+            .ok_or(Error::InvalidDataFormat)?;
+        if footer_len > footer.payload_len {
+            return Err(Error::InvalidDataFormat);
+        }
+        let mut payload = Payload {
+            src,
+            start,
+            footer,
+            header: Header {
+                scheme: Scheme::Plain,
+                nonce: None,
+            },
+            key: None,
+        };
+        let header = payload.read_tail(payload.footer.header_len + payload.footer.manifest_len)?;
+        payload.header = postcard::from_bytes(&header[..payload.footer.header_len as usize])
+            .set_error(Error::InvalidDataFormat)?;
+        // refused before a password is asked for
+        if let Scheme::Password { kdf, .. } = &payload.header.scheme {
+            kdf.check()?;
+        }
+        Ok(payload)
+    }
+
+    pub fn scheme(&self) -> &Scheme {
+        &self.header.scheme
+    }
+
+    /// Digest recorded in the footer, checked by `verify`
+    pub fn digest(&self) -> &[u8; DIGEST_SIZE] {
+        &self.footer.digest
+    }
+
+    /// Signature of the digest, None when the capsule is unsigned
+    pub fn signature(&self) -> Option<&Signature> {
+        self.footer.signature.as_ref()
+    }
+
+    /// Format version of the payload, at most `FORMAT_VERSION`
+    pub fn version(&self) -> u16 {
+        self.footer.version
+    }
+
+    /// Hashes the runtime and the payload and compares them with the footer
+    /// digest. Reads the whole executable
+    pub fn verify(&mut self) -> Result<(), Error> {
+        let len = self.start + self.footer.payload_len + LENGTHS_SIZE as u64;
+        self.src
+            .seek(SeekFrom::Start(0))
+            .set_error(Error::IntegrityCheckFailed)?;
+        let mut hasher = Sha256::new();
+        let hashed = io::copy(&mut (&mut self.src).take(len), &mut hasher)
+            .set_error(Error::IntegrityCheckFailed)?;
+        match hashed == len && hasher.finalize().as_slice() == self.footer.digest {
+            true => Ok(()),
+            false => Err(Error::IntegrityCheckFailed),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.header.scheme.is_encrypted()
+    }
+
+    /// Key of the payload once unlocked
+    pub fn key(&self) -> Option<&[u8; KEY_SIZE]> {
+        self.key.as_ref()
+    }
+
+    /// Gets the key of an encrypted payload from `secret`, the password or the
+    /// hex of the key or private key depending on the scheme. Checked when the
+    /// manifest is read
+    pub fn unlock(&mut self, secret: &str) -> Result<(), Error> {
+        let key = |secret: &str| keys::parse_key(secret).ok_or(Error::InvalidPassword);
+        self.key = match &self.header.scheme {
+            Scheme::Plain => None,
+            Scheme::Password { kdf, salt } => Some(kdf.derive(secret, salt)?),
+            Scheme::Key => Some(key(secret)?),
+            Scheme::Recipient {
+                ephemeral,
+                recipient,
+            } => Some(keys::open_for(&key(secret)?, ephemeral, recipient)?),
+        };
+        Ok(())
+    }
+
+    /// Reads `len` bytes starting `len` bytes before the end of the payload
+    fn read_tail(&mut self, len: u64) -> Result<Vec<u8>, Error> {
+        let offset = self.start + self.footer.payload_len - len;
+        self.src
+            .seek(SeekFrom::Start(offset))
+            .set_error(Error::InvalidDataFormat)?;
+        let mut bytes = vec![0u8; len as usize];
+        self.src
+            .read_exact(&mut bytes)
+            .set_error(Error::InvalidDataFormat)?;
+        Ok(bytes)
+    }
+
+    pub fn settings(&mut self) -> Result<Settings, Error> {
+        let footer = &self.footer;
+        let mut settings =
+            self.read_tail(footer.settings_len + footer.header_len + footer.manifest_len)?;
+        settings.truncate(self.footer.settings_len as usize);
+        postcard::from_bytes(&settings).set_error(Error::InvalidDataFormat)
+    }
+
+    pub fn manifest(&mut self) -> Result<Capsule, Error> {
+        let mut manifest = self.read_tail(self.footer.manifest_len)?;
+        if self.is_encrypted() {
+            let nonce = self.header.nonce.ok_or(Error::InvalidDataFormat)?;
+            let key = self.key.ok_or(Error::InvalidPassword)?;
+            manifest = decrypt(&key, &nonce, &manifest)?;
+        }
+        postcard::from_bytes(&manifest).set_error(Error::InvalidDataFormat)
+    }
+
+    /// Streams the content of `blob` into `out`, decrypting chunk by chunk
+    pub fn copy_blob(&mut self, blob: &Blob, out: &mut impl Write) -> io::Result<()> {
+        self.src.seek(SeekFrom::Start(self.start + blob.offset))?;
+        let invalid = || io::Error::from(io::ErrorKind::InvalidData);
+        let Some(prefix) = &blob.nonce else {
+            let copied = io::copy(&mut (&mut self.src).take(blob.size), out)?;
+            return match copied == blob.size {
+                true => Ok(()),
+                false => Err(invalid()),
+            };
+        };
+
+        let cipher = cipher(self.key.as_ref().ok_or_else(invalid)?);
+        let mut buf = Vec::with_capacity(CHUNK_SIZE + TAG_SIZE);
+        let mut remaining = blob.size;
+        let mut counter = 0u32;
+        loop {
+            let len = remaining.min(CHUNK_SIZE as u64);
+            remaining -= len;
+            let last = remaining == 0;
+            buf.clear();
+            let sealed = len + TAG_SIZE as u64;
+            if (&mut self.src).take(sealed).read_to_end(&mut buf)? as u64 != sealed {
+                return Err(invalid());
+            }
+            let nonce = chunk_nonce(prefix, counter, last);
+            cipher
+                .decrypt_in_place(Nonce::from_slice(&nonce), b"", &mut buf)
+                .map_err(|_| invalid())?;
+            out.write_all(&buf)?;
+            if last {
+                return Ok(());
+            }
+            counter = counter.checked_add(1).ok_or_else(invalid)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CHUNK_SIZE, FOOTER_SIZE, FORMAT_VERSION, Lock, Payload, PayloadWriter, hex};
+    use crate::keys::{self, Kdf};
+    use crate::{Capsule, Error, MAGIC_NUMBER};
+    use std::io::Cursor;
+
+    fn build_payload(lock: Option<&Lock>, files: &[&[u8]]) -> (Vec<u8>, Vec<String>) {
+        let capsule: Capsule =
+            serde_json::from_str(r#"{"version": "1.0.0", "data_dir": "/srv/app"}"#).unwrap();
+        // the payload follows the runtime in the executable
+        let mut writer = PayloadWriter::new(vec![], b"runtime", lock).unwrap();
+        let hashes = files
+            .iter()
+            .map(|f| writer.add(&mut Cursor::new(f)).unwrap())
+            .collect();
+        (writer.finish(capsule, None).unwrap(), hashes)
+    }
+
+    fn read_blob(payload: &mut Payload<Cursor<Vec<u8>>>, capsule: &Capsule, hash: &str) -> Vec<u8> {
+        let blob = &capsule.blobs.as_ref().unwrap()[hash];
+        let mut out = vec![];
+        payload.copy_blob(blob, &mut out).unwrap();
+        out
+    }
+
+    fn password(password: &str, kdf: Kdf) -> Lock {
+        Lock::Password {
+            password: password.to_string(),
+            kdf,
+        }
+    }
+
+    /// Cheap parameters, they are read from the header
+    const FAST_PBKDF2: Kdf = Kdf::Pbkdf2Sha256 { iterations: 1000 };
+    const FAST_ARGON2ID: Kdf = Kdf::Argon2id {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    #[test]
+    fn payload_round_trip() {
+        let big: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let files: [&[u8]; 4] = [b"hello", &big, b"", b"hello"];
+        let key = keys::generate_key();
+        let private = keys::generate_key();
+        let locks = [
+            (None, ""),
+            (Some(password("secret", FAST_PBKDF2)), "secret"),
+            (Some(password("secret", FAST_ARGON2ID)), "secret"),
+            (Some(Lock::Key(key)), &hex(&key)),
+            (
+                Some(Lock::Recipient(keys::public_key(&private))),
+                &hex(&private),
+            ),
+        ];
+        for (lock, secret) in &locks {
+            let (bytes, hashes) = build_payload(lock.as_ref(), &files);
+            assert_eq!(hashes[0], hashes[3]);
+            let mut payload = Payload::new(Cursor::new(bytes)).unwrap();
+            payload.verify().unwrap();
+            assert_eq!(payload.is_encrypted(), lock.is_some());
+            // readable before unlocking
+            let settings = payload.settings().unwrap();
+            assert_eq!(settings.data_dir.as_deref(), Some("/srv/app"));
+            if payload.is_encrypted() {
+                assert!(matches!(payload.manifest(), Err(Error::InvalidPassword)));
+                payload.unlock(secret).unwrap();
+            }
+            let capsule = payload.manifest().unwrap();
+            assert_eq!(capsule.blobs.as_ref().unwrap().len(), 3);
+            for (hash, content) in hashes.iter().zip(files) {
+                assert_eq!(read_blob(&mut payload, &capsule, hash), content);
+            }
+        }
+    }
+
+    #[test]
+    fn payload_rejects_wrong_password() {
+        let (bytes, _) = build_payload(Some(&password("secret", Kdf::PBKDF2)), &[b"hello"]);
+        let mut payload = Payload::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(payload.scheme().to_string(), "password (pbkdf2-sha256)");
+        payload.unlock("not the secret").unwrap();
+        assert!(matches!(payload.manifest(), Err(Error::InvalidPassword)));
+    }
+
+    #[test]
+    fn payload_rejects_wrong_keys() {
+        let key = keys::generate_key();
+        let (bytes, _) = build_payload(Some(&Lock::Key(key)), &[b"hello"]);
+        let mut payload = Payload::new(Cursor::new(bytes)).unwrap();
+        assert!(matches!(
+            payload.unlock("not hex"),
+            Err(Error::InvalidPassword)
+        ));
+        payload.unlock(&hex(&keys::generate_key())).unwrap();
+        assert!(matches!(payload.manifest(), Err(Error::InvalidPassword)));
+
+        let recipient = keys::public_key(&keys::generate_key());
+        let (bytes, _) = build_payload(Some(&Lock::Recipient(recipient)), &[b"hello"]);
+        let mut payload = Payload::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(payload.scheme().prompt(), "Enter private key: ");
+        assert!(matches!(
+            payload.unlock(&hex(&keys::generate_key())),
+            Err(Error::InvalidPassword)
+        ));
+        // low order points would give a key anyone can compute
+        assert!(matches!(
+            PayloadWriter::new(vec![], b"", Some(&Lock::Recipient([0u8; 32]))),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn payload_detects_tampering() {
+        let (bytes, _) = build_payload(None, &[b"hello"]);
+        let footer = bytes.len() - FOOTER_SIZE;
+        // runtime, blob, footer lengths
+        for i in [0, 8, footer + 2] {
+            let mut tampered = bytes.clone();
+            tampered[i] ^= 1;
+            let verified = Payload::new(Cursor::new(tampered)).and_then(|mut p| p.verify());
+            assert!(verified.is_err(), "byte {} changed", i);
+        }
+        let mut prefixed = b"x".to_vec();
+        prefixed.extend_from_slice(&bytes);
+        let mut payload = Payload::new(Cursor::new(prefixed)).unwrap();
+        assert!(matches!(payload.verify(), Err(Error::IntegrityCheckFailed)));
+    }
+
+    #[test]
+    fn payload_lengths_are_checked() {
+        let (bytes, _) = build_payload(None, &[b"hello"]);
+        let lengths = bytes.len() - FOOTER_SIZE;
+        let len = |i: usize| lengths + i * 8..lengths + i * 8 + 8;
+        // settings, header and manifest lengths wrap around to 2
+        let mut wrapping = bytes.clone();
+        wrapping[len(1)].copy_from_slice(&u64::MAX.to_le_bytes());
+        wrapping[len(2)].copy_from_slice(&2u64.to_le_bytes());
+        wrapping[len(3)].copy_from_slice(&1u64.to_le_bytes());
+        assert!(matches!(
+            Payload::new(Cursor::new(wrapping)),
+            Err(Error::InvalidDataFormat)
+        ));
+        // longer than the whole payload
+        let mut long = bytes.clone();
+        long[len(3)].copy_from_slice(&(bytes.len() as u64).to_le_bytes());
+        assert!(matches!(
+            Payload::new(Cursor::new(long)),
+            Err(Error::InvalidDataFormat)
+        ));
+    }
+
+    #[test]
+    fn payload_format_is_versioned() {
+        let (mut bytes, _) = build_payload(None, &[b"hello"]);
+        let version = bytes.len() - MAGIC_NUMBER.len() - 2;
+        bytes[version..version + 2].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            Payload::new(Cursor::new(bytes)),
+            Err(Error::UnsupportedFormat(v)) if v == FORMAT_VERSION + 1
+        ));
+        assert!(matches!(
+            Payload::new(Cursor::new(b"runtime only".to_vec())),
+            Err(Error::NoData)
+        ));
+    }
+
+    #[test]
+    fn payload_kdf_costs_are_bounded() {
+        let kdf = Kdf::Argon2id {
+            memory_kib: 16 * 1024,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let (mut bytes, _) = build_payload(Some(&password("secret", kdf)), &[b"hello"]);
+        // same varint length, 2 GiB of memory
+        let encoded = postcard::to_allocvec(&kdf).unwrap();
+        let at = bytes
+            .windows(encoded.len())
+            .position(|w| w == encoded)
+            .unwrap();
+        bytes[at + 1..at + 4].copy_from_slice(&[0xff, 0xff, 0x7f]);
+        assert!(matches!(
+            Payload::new(Cursor::new(bytes)),
+            Err(Error::InvalidDataFormat)
+        ));
+    }
+}
@@ -0,0 +1,43 @@
+//! Temporary directories for the tests of every crate, behind the `testing`
+//! feature. They are removed when dropped, after a failed assertion too.
+
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// Empty directory unique to a test
+pub struct Scratch(PathBuf);
+
+impl Scratch {
+    /// `capsules-<name>-<pid>` in the temp dir, `name` is unique among the
+    /// tests of a crate
+    pub fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("capsules-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Scratch(dir)
+    }
+
+    /// Writes `content` to `relative`, creating its parents
+    pub fn write(&self, relative: &str, content: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.0.join(relative);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+        path
+    }
+}
+
+impl Deref for Scratch {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
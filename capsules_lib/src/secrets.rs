@@ -0,0 +1,281 @@
+//! Values kept out of the manifest and of the extracted files. The compiler seals
+//! them on their own, so they stay sealed in plain capsules, and the supervisor
+//! only hands them to the processes using them.
+
+use crate::env::is_name;
+use crate::keys::{self, KEY_SIZE, Kdf, SALT_SIZE};
+use crate::{Error, SetError, decrypt, encrypt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// How a secret reaches a process
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Injection {
+    /// Env var named after the secret
+    #[default]
+    Env,
+    /// File named after the secret in `$CAPSULE_SECRETS_DIR`, a tmpfs directory
+    File,
+}
+
+/// A secret of the capsule config, the value or the value and how it is injected.
+/// Not Debug so values do not end up in logs
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Secret {
+    Value(String),
+    Detailed {
+        value: String,
+        /// Defaults to env
+        inject: Option<Injection>,
+    },
+}
+
+impl Secret {
+    pub fn value(&self) -> &str {
+        match self {
+            Secret::Value(value) | Secret::Detailed { value, .. } => value,
+        }
+    }
+
+    pub fn injection(&self) -> Injection {
+        match self {
+            Secret::Value(_) => Injection::Env,
+            Secret::Detailed { inject, .. } => inject.unwrap_or_default(),
+        }
+    }
+}
+
+/// Secret values once unsealed
+#[derive(Serialize, Deserialize, Default)]
+pub struct SecretValues {
+    global: BTreeMap<String, String>,
+    processes: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+/// A secret given to a process
+pub struct ProcessSecret {
+    pub name: String,
+    pub injection: Injection,
+    pub value: String,
+}
+
+/// What the secrets are sealed with
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum SecretsKey {
+    /// The key of the payload, which is encrypted
+    Payload,
+    /// A password of their own
+    Password { kdf: Kdf, salt: [u8; SALT_SIZE] },
+}
+
+impl SecretsKey {
+    /// A password with a fresh salt
+    pub fn password(kdf: Kdf) -> Self {
+        SecretsKey::Password {
+            kdf,
+            salt: keys::salt(),
+        }
+    }
+
+    /// The payload key or the key derived from `password`
+    pub fn resolve(
+        &self,
+        payload: Option<&[u8; KEY_SIZE]>,
+        password: Option<&str>,
+    ) -> Result<[u8; KEY_SIZE], Error> {
+        match self {
+            SecretsKey::Payload => payload.copied().ok_or(Error::InvalidPassword),
+            SecretsKey::Password { kdf, salt } => {
+                kdf.derive(password.ok_or(Error::InvalidPassword)?, salt)
+            }
+        }
+    }
+}
+
+/// Names and injections of the secrets in clear, their values sealed
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SealedSecrets {
+    /// Global secrets, given to the processes listing them in `use_secrets`
+    pub global: BTreeMap<String, Injection>,
+    /// Process name -> secrets of this process only
+    pub processes: BTreeMap<String, BTreeMap<String, Injection>>,
+    pub key: SecretsKey,
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+
+impl SealedSecrets {
+    /// Seals the values with `key`, obtained from `sealed_with`
+    pub fn seal(
+        key: &[u8; KEY_SIZE],
+        sealed_with: SecretsKey,
+        global: HashMap<String, Secret>,
+        processes: HashMap<String, HashMap<String, Secret>>,
+    ) -> Result<Self, Error> {
+        let injections = |secrets: &HashMap<String, Secret>| {
+            secrets
+                .iter()
+                .map(|(name, secret)| (name.clone(), secret.injection()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        let values = |secrets: HashMap<String, Secret>| {
+            secrets
+                .into_iter()
+                .map(|(name, secret)| (name, secret.value().to_string()))
+                .collect::<BTreeMap<_, _>>()
+        };
+
+        let mut names = global
+            .keys()
+            .chain(processes.values().flat_map(|s| s.keys()));
+        if let Some(name) = names.find(|name| !is_name(name)) {
+            return Err(Error::InvalidConfig(format!(
+                "invalid secret name {:?}, use letters, digits and _",
+                name
+            )));
+        }
+
+        let sealed_global = injections(&global);
+        let sealed_processes = processes
+            .iter()
+            .map(|(process, secrets)| (process.clone(), injections(secrets)))
+            .collect();
+        let values = SecretValues {
+            global: values(global),
+            processes: processes
+                .into_iter()
+                .map(|(process, secrets)| (process, values(secrets)))
+                .collect(),
+        };
+        let plaintext = postcard::to_allocvec(&values).set_error(Error::InternalError)?;
+        let (nonce, ciphertext) = encrypt(key, &plaintext)?;
+        Ok(SealedSecrets {
+            global: sealed_global,
+            processes: sealed_processes,
+            key: sealed_with,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    /// Sealed with a password other than the payload one, asked for on top of it
+    pub fn own_password(&self) -> bool {
+        matches!(self.key, SecretsKey::Password { .. })
+    }
+
+    /// `key` is what `SecretsKey::resolve` returns
+    pub fn unseal(&self, key: &[u8; KEY_SIZE]) -> Result<SecretValues, Error> {
+        let plaintext = decrypt(key, &self.nonce, &self.ciphertext)?;
+        postcard::from_bytes(&plaintext).set_error(Error::InvalidDataFormat)
+    }
+
+    /// Names of the secrets given to `process`, its own ones override the global ones
+    pub fn names(&self, process: &str, uses: &[String]) -> BTreeMap<String, Injection> {
+        let mut names: BTreeMap<_, _> = uses
+            .iter()
+            .filter_map(|name| Some((name.clone(), *self.global.get(name)?)))
+            .collect();
+        names.extend(
+            self.processes
+                .get(process)
+                .into_iter()
+                .flatten()
+                .map(|(n, i)| (n.clone(), *i)),
+        );
+        names
+    }
+
+    /// Secrets given to `process`, sorted by name
+    pub fn for_process(
+        &self,
+        values: &SecretValues,
+        process: &str,
+        uses: &[String],
+    ) -> Vec<ProcessSecret> {
+        self.names(process, uses)
+            .into_iter()
+            .filter_map(|(name, injection)| {
+                let value = values
+                    .processes
+                    .get(process)
+                    .and_then(|own| own.get(&name))
+                    .or_else(|| values.global.get(&name))?;
+                Some(ProcessSecret {
+                    value: value.clone(),
+                    name,
+                    injection,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Injection, SealedSecrets, SecretsKey};
+    use crate::{Capsule, Error};
+
+    #[test]
+    fn secrets_are_sealed_and_scoped() {
+        let capsule: Capsule = serde_json::from_str(
+            r#"{
+                "version": "1.0.0",
+                "secrets": {
+                    "DB_PASSWORD": "hunter2",
+                    "TLS_KEY": {"value": "-----BEGIN KEY-----", "inject": "file"}
+                },
+                "processes": {
+                    "api": {"cmd": "api", "use_secrets": ["DB_PASSWORD", "TLS_KEY"]},
+                    "worker": {"cmd": "worker", "secrets": {"DB_PASSWORD": "worker-only"}},
+                    "cron": {"cmd": "cron"}
+                }
+            }"#,
+        )
+        .unwrap();
+        let processes = capsule.processes.unwrap();
+        let own = processes
+            .iter()
+            .filter_map(|(name, p)| Some((name.clone(), p.secrets.clone()?)))
+            .collect();
+        let key = [7u8; 32];
+        let sealed =
+            SealedSecrets::seal(&key, SecretsKey::Payload, capsule.secrets.unwrap(), own).unwrap();
+        let bytes = postcard::to_allocvec(&sealed).unwrap();
+        assert!(!bytes.windows(7).any(|w| w == b"hunter2"));
+        assert!(!sealed.own_password());
+        assert!(matches!(
+            sealed.unseal(&[8u8; 32]),
+            Err(Error::InvalidPassword)
+        ));
+
+        let values = sealed.unseal(&key).unwrap();
+        let given = |name: &str| -> Vec<_> {
+            let uses = processes[name].use_secrets.clone().unwrap_or_default();
+            sealed
+                .for_process(&values, name, &uses)
+                .into_iter()
+                .map(|s| (s.name, s.injection, s.value))
+                .collect()
+        };
+        assert_eq!(
+            given("api"),
+            [
+                ("DB_PASSWORD".into(), Injection::Env, "hunter2".into()),
+                (
+                    "TLS_KEY".into(),
+                    Injection::File,
+                    "-----BEGIN KEY-----".into()
+                ),
+            ]
+        );
+        assert_eq!(
+            given("worker"),
+            [("DB_PASSWORD".into(), Injection::Env, "worker-only".into())]
+        );
+        assert!(given("cron").is_empty());
+    }
+}
@@ -0,0 +1,260 @@
+//! Ed25519 signatures of capsules. The compiler signs the footer digest, which
+//! covers the runtime, the settings, the header and the manifest, along with
+//! the magic number and the format version of the footer, and stores
+//! the signature in the footer with the id of its key. Runtimes only accept it
+//! from the keys they trust.
+
+use crate::keys::{KEY_SIZE, parse_key};
+use crate::payload::{Payload, hex};
+use crate::{Error, MAGIC_NUMBER, SetError};
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fmt::Display;
+use std::fs;
+use std::io::{Read, Seek};
+use std::path::Path;
+
+pub const KEY_ID_SIZE: usize = 8;
+pub const SIGNATURE_SIZE: usize = 64;
+
+/// Signatures of capsules can not be replayed as signatures of anything else
+const CONTEXT: &[u8] = b"capsules signature v1";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Signature {
+    pub key_id: [u8; KEY_ID_SIZE],
+    pub signature: [u8; SIGNATURE_SIZE],
+}
+
+/// Start of the SHA-256 of the public key
+pub fn key_id(public: &[u8; KEY_SIZE]) -> [u8; KEY_ID_SIZE] {
+    let mut id = [0u8; KEY_ID_SIZE];
+    id.copy_from_slice(&Sha256::digest(public)[..KEY_ID_SIZE]);
+    id
+}
+
+/// Public key of the signing key `secret`, the one runtimes trust
+pub fn public_key(secret: &[u8; KEY_SIZE]) -> [u8; KEY_SIZE] {
+    SigningKey::from_bytes(secret).verifying_key().to_bytes()
+}
+
+pub fn sign(secret: &[u8; KEY_SIZE], version: u16, digest: &[u8; 32]) -> Signature {
+    let key = SigningKey::from_bytes(secret);
+    Signature {
+        key_id: key_id(&key.verifying_key().to_bytes()),
+        signature: key.sign(&message(version, digest)).to_bytes(),
+    }
+}
+
+/// The footer fields outside of the digest are signed too
+fn message(version: u16, digest: &[u8; 32]) -> Vec<u8> {
+    [
+        CONTEXT,
+        MAGIC_NUMBER.as_slice(),
+        &version.to_le_bytes(),
+        digest.as_slice(),
+    ]
+    .concat()
+}
+
+/// Public keys in hex separated by whitespace or commas, `#` starts a comment
+pub fn parse_trusted_keys(content: &str) -> Result<Vec<[u8; KEY_SIZE]>, Error> {
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+        .filter(|key| !key.is_empty())
+        .map(|key| {
+            parse_key(key)
+                .ok_or_else(|| Error::InvalidConfig(format!("invalid trusted key {:?}", key)))
+        })
+        .collect()
+}
+
+/// Trusted keys written to `path`, in the format of `parse_trusted_keys`
+pub fn read_trusted_keys(path: &Path) -> Result<Vec<[u8; KEY_SIZE]>, Error> {
+    let content =
+        fs::read_to_string(path).set_error(Error::CouldNotReadFile(path.display().to_string()))?;
+    parse_trusted_keys(&content)
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureStatus {
+    Unsigned,
+    /// Signed by a key missing from the trust list
+    Untrusted,
+    /// Signed with the id of a trusted key, but not by it
+    Invalid,
+    Trusted,
+}
+
+impl SignatureStatus {
+    pub fn check(
+        signature: Option<&Signature>,
+        version: u16,
+        digest: &[u8; 32],
+        trusted: &[[u8; KEY_SIZE]],
+    ) -> Self {
+        let Some(signature) = signature else {
+            return SignatureStatus::Unsigned;
+        };
+        let Some(key) = trusted.iter().find(|key| key_id(key) == signature.key_id) else {
+            return SignatureStatus::Untrusted;
+        };
+        let verified = VerifyingKey::from_bytes(key).is_ok_and(|key| {
+            let signature = ed25519_dalek::Signature::from_bytes(&signature.signature);
+            key.verify_strict(&message(version, digest), &signature)
+                .is_ok()
+        });
+        match verified {
+            true => SignatureStatus::Trusted,
+            false => SignatureStatus::Invalid,
+        }
+    }
+}
+
+impl Display for SignatureStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignatureStatus::Unsigned => write!(f, "unsigned"),
+            SignatureStatus::Untrusted => write!(f, "signed by an untrusted key"),
+            SignatureStatus::Invalid => write!(f, "invalid signature"),
+            SignatureStatus::Trusted => write!(f, "signed by a trusted key"),
+        }
+    }
+}
+
+/// Printed by `verify` on the compiler and the runtime
+#[derive(Serialize)]
+pub struct Verification {
+    /// SHA-256 recorded in the footer
+    pub digest: String,
+    /// The digest matches the runtime and the payload
+    pub intact: bool,
+    /// Id of the signing key, None when unsigned
+    pub signer: Option<String>,
+    pub signature: SignatureStatus,
+}
+
+impl Verification {
+    pub fn new<R: Read + Seek>(payload: &mut Payload<R>, trusted: &[[u8; KEY_SIZE]]) -> Self {
+        let signature = payload.signature().copied();
+        Verification {
+            digest: hex(payload.digest()),
+            intact: payload.verify().is_ok(),
+            signer: signature.map(|s| hex(&s.key_id)),
+            signature: SignatureStatus::check(
+                signature.as_ref(),
+                payload.version(),
+                payload.digest(),
+                trusted,
+            ),
+        }
+    }
+
+    /// Err when the capsule is damaged, or not signed by a trusted key while
+    /// there are some
+    pub fn result(&self, trusted: &[[u8; KEY_SIZE]]) -> Result<(), Error> {
+        if !self.intact {
+            return Err(Error::IntegrityCheckFailed);
+        }
+        if trusted.is_empty() || self.signature == SignatureStatus::Trusted {
+            return Ok(());
+        }
+        Err(Error::SignatureCheckFailed(self.signature_line()))
+    }
+
+    fn signature_line(&self) -> String {
+        match &self.signer {
+            Some(signer) => format!("{} (key {})", self.signature, signer),
+            None => self.signature.to_string(),
+        }
+    }
+}
+
+impl Display for Verification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Digest   : {}", self.digest)?;
+        writeln!(
+            f,
+            "Integrity: {}",
+            if self.intact { "ok" } else { "failed" }
+        )?;
+        writeln!(f, "Signature: {}", self.signature_line())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SignatureStatus, Verification, key_id, parse_trusted_keys, public_key};
+    use crate::keys;
+    use crate::payload::{FORMAT_VERSION, Payload, PayloadWriter, hex};
+    use crate::{Capsule, Error, MAGIC_NUMBER};
+    use std::io::Cursor;
+
+    #[test]
+    fn signatures_are_checked_against_trusted_keys() {
+        let signing_key = keys::generate_key();
+        let public = public_key(&signing_key);
+        let capsule: Capsule = serde_json::from_str(r#"{"version": "1.0.0"}"#).unwrap();
+        let writer = PayloadWriter::new(vec![], b"runtime", None).unwrap();
+        let bytes = writer.finish(capsule, Some(&signing_key)).unwrap();
+
+        let other = public_key(&keys::generate_key());
+        let trusted = parse_trusted_keys(&format!(
+            "# build pipeline\n{}, {} # old key\n",
+            hex(&other),
+            hex(&public)
+        ))
+        .unwrap();
+        assert_eq!(trusted, [other, public]);
+        assert!(parse_trusted_keys("abc").is_err());
+
+        let mut payload = Payload::new(Cursor::new(bytes.clone())).unwrap();
+        let verification = Verification::new(&mut payload, &trusted);
+        assert!(verification.intact);
+        assert_eq!(verification.signature, SignatureStatus::Trusted);
+        assert_eq!(
+            verification.signer.as_deref(),
+            Some(hex(&key_id(&public)).as_str())
+        );
+        assert!(verification.result(&trusted).is_ok());
+
+        let verification = Verification::new(&mut payload, &[other]);
+        assert_eq!(verification.signature, SignatureStatus::Untrusted);
+        assert!(matches!(
+            verification.result(&[other]),
+            Err(Error::SignatureCheckFailed(_))
+        ));
+
+        // the signature is not covered by the digest
+        let mut forged = bytes.clone();
+        let last_signature_byte = forged.len() - MAGIC_NUMBER.len() - 2 - 1;
+        forged[last_signature_byte] ^= 1;
+        let mut payload = Payload::new(Cursor::new(forged)).unwrap();
+        let verification = Verification::new(&mut payload, &trusted);
+        assert!(verification.intact);
+        assert_eq!(verification.signature, SignatureStatus::Invalid);
+
+        // neither is the format version, older ones are still read
+        let mut downgraded = bytes.clone();
+        let version = downgraded.len() - MAGIC_NUMBER.len() - 2;
+        downgraded[version..version + 2].copy_from_slice(&(FORMAT_VERSION - 1).to_le_bytes());
+        let mut payload = Payload::new(Cursor::new(downgraded)).unwrap();
+        let verification = Verification::new(&mut payload, &trusted);
+        assert!(verification.intact);
+        assert_eq!(verification.signature, SignatureStatus::Invalid);
+
+        let capsule: Capsule = serde_json::from_str(r#"{"version": "1.0.0"}"#).unwrap();
+        let writer = PayloadWriter::new(vec![], b"runtime", None).unwrap();
+        let unsigned = writer.finish(capsule, None).unwrap();
+        let mut payload = Payload::new(Cursor::new(unsigned)).unwrap();
+        let verification = Verification::new(&mut payload, &trusted);
+        assert_eq!(verification.signature, SignatureStatus::Unsigned);
+        assert!(verification.result(&trusted).is_err());
+        // nothing to check against
+        assert!(verification.result(&[]).is_ok());
+    }
+}
@@ -0,0 +1,421 @@
+//! Directory holding the extracted files, logs, socket and token of a capsule.
+//!
+//! The default instance uses the data directory itself, named instances use
+//! `<data dir>/instances/<name>` so they never share state.
+//!
+//! Resolved once at startup, the first one set wins:
+//! `--data-dir`, `CAPSULE_DATA_DIR`, the `data_dir` of the capsule, then
+//! `/var/lib/<name>` as root, `$XDG_STATE_HOME/<name>` or `~/.local/state/<name>`
+//! (`%LOCALAPPDATA%\<name>` on Windows), `<name>` being the executable name
+//! followed by a hash of its path, so that copies of a binary do not share state.
+//!
+//! The directory can hold other files, `--data-dir ~` or a `data_dir` of `.`,
+//! so what the capsule creates in it is listed in `.capsule-owned` and
+//! tear-down removes nothing else.
+
+use capsules_lib::payload::{Payload, hex};
+use capsules_lib::{DEFAULT_INSTANCE, Error, SetError, check_instance_name, safe_relative_path};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+pub const DATA_DIR_ENV: &str = "CAPSULE_DATA_DIR";
+
+const INSTANCES_DIR: &str = "instances";
+
+/// Top level entries the capsule created in a directory, one per line
+const OWNED_FILE: &str = ".capsule-owned";
+/// Entry of `OWNED_FILE` for the directory itself
+const OWNED_DIR: &str = ".";
+
+struct DataDir {
+    base: PathBuf,
+    instance: String,
+}
+
+static DATA_DIR: OnceLock<DataDir> = OnceLock::new();
+
+pub fn init(cli: Option<PathBuf>, instance: Option<String>) -> Result<(), Error> {
+    let instance = instance.unwrap_or_else(|| DEFAULT_INSTANCE.to_string());
+    check_instance_name(&instance)?;
+    let dir = DataDir {
+        base: resolve(cli)?,
+        instance,
+    };
+    DATA_DIR.set(dir).set_error(Error::InternalError)
+}
+
+fn data_dir() -> Result<&'static DataDir, Error> {
+    DATA_DIR.get().ok_or(Error::InternalError)
+}
+
+/// Directory of the current instance
+pub fn get() -> Result<PathBuf, Error> {
+    let dir = data_dir()?;
+    Ok(instance_dir(&dir.base, &dir.instance))
+}
+
+/// Shared by every instance, `--data-dir` of the supervisor
+pub fn base() -> Result<PathBuf, Error> {
+    Ok(data_dir()?.base.clone())
+}
+
+pub fn instance() -> Result<&'static str, Error> {
+    Ok(&data_dir()?.instance)
+}
+
+fn instance_dir(base: &Path, instance: &str) -> PathBuf {
+    match instance {
+        DEFAULT_INSTANCE => base.to_path_buf(),
+        name => base.join(INSTANCES_DIR).join(name),
+    }
+}
+
+/// Instances that have been started at least once, with their directory
+pub fn instances() -> Result<Vec<(String, PathBuf)>, Error> {
+    let base = base()?;
+    let mut instances = vec![];
+    if base.join("capsule.token").exists() {
+        instances.push((DEFAULT_INSTANCE.to_string(), base.clone()));
+    }
+    if let Ok(entries) = fs::read_dir(base.join(INSTANCES_DIR)) {
+        let mut named: Vec<_> = entries
+            .flatten()
+            .filter(|e| e.path().is_dir())
+            .filter_map(|e| e.file_name().into_string().ok())
+            .filter(|name| check_instance_name(name).is_ok())
+            .map(|name| {
+                let dir = instance_dir(&base, &name);
+                (name, dir)
+            })
+            .collect();
+        named.sort();
+        instances.extend(named);
+    }
+    Ok(instances)
+}
+
+fn read_owned(dir: &Path) -> Option<BTreeSet<String>> {
+    let content = fs::read_to_string(dir.join(OWNED_FILE)).ok()?;
+    Some(content.lines().map(str::to_string).collect())
+}
+
+fn write_owned(dir: &Path, owned: &BTreeSet<String>) -> Result<(), Error> {
+    let path = dir.join(OWNED_FILE);
+    let content: String = owned.iter().map(|name| format!("{}\n", name)).collect();
+    fs::write(&path, content).set_error(Error::CouldNotWriteFile(path.display().to_string()))
+}
+
+/// Creates `dir` and records the entries of `names` that do not exist yet,
+/// the others belong to someone else
+fn claim_in(dir: &Path, names: &[String]) -> Result<(), Error> {
+    let mut owned = read_owned(dir).unwrap_or_default();
+    if dir.symlink_metadata().is_err() {
+        fs::create_dir_all(dir).set_error(Error::CouldNotCreatePath(dir.display().to_string()))?;
+        owned.insert(OWNED_DIR.to_string());
+    }
+    for name in names {
+        if dir.join(name).symlink_metadata().is_err() {
+            owned.insert(name.clone());
+        }
+    }
+    write_owned(dir, &owned)
+}
+
+/// Records the paths the supervisor is about to create in the directory of
+/// the instance, relative to it. Only their first segment is kept
+pub fn claim(paths: impl IntoIterator<Item = String>) -> Result<(), Error> {
+    let dir = data_dir()?;
+    if dir.instance != DEFAULT_INSTANCE {
+        claim_in(&dir.base, &[INSTANCES_DIR.to_string()])?;
+    }
+    let names: Vec<String> = paths
+        .into_iter()
+        .filter_map(|path| safe_relative_path(&path).ok())
+        .map(|path| path.split('/').next().unwrap_or_default().to_string())
+        .collect();
+    claim_in(&get()?, &names)
+}
+
+/// Err when one of `paths`, relative to the directory of the instance, exists
+/// outside of what the capsule created. Extracting it would replace it
+pub fn check_owned(paths: impl IntoIterator<Item = String>) -> Result<(), Error> {
+    check_owned_in(&get()?, paths)
+}
+
+fn check_owned_in(dir: &Path, paths: impl IntoIterator<Item = String>) -> Result<(), Error> {
+    let owned = read_owned(dir).unwrap_or_default();
+    // extraction refuses unsafe paths on its own
+    for path in paths
+        .into_iter()
+        .filter_map(|p| safe_relative_path(&p).ok())
+    {
+        let name = path.split('/').next().unwrap_or_default();
+        let path = dir.join(&path);
+        if !owned.contains(name) && path.symlink_metadata().is_ok() {
+            return Err(Error::NotOwned(path.display().to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Removes what the capsule created for the current instance, the default one
+/// keeps the named ones. Refuses a directory the capsule did not create
+pub fn clear() -> Result<(), Error> {
+    let dir = data_dir()?;
+    if dir.instance == DEFAULT_INSTANCE {
+        return release(&dir.base, &[INSTANCES_DIR]);
+    }
+    release(&get()?, &[])?;
+    // once the last named instance is gone
+    let Some(mut owned) = read_owned(&dir.base) else {
+        return Ok(());
+    };
+    if owned.contains(INSTANCES_DIR) && fs::remove_dir(dir.base.join(INSTANCES_DIR)).is_ok() {
+        owned.remove(INSTANCES_DIR);
+        forget(&dir.base, owned)?;
+    }
+    Ok(())
+}
+
+/// Removes the owned entries of `dir` but `keep`, then `dir` itself when the
+/// capsule created it and nothing else is left
+fn release(dir: &Path, keep: &[&str]) -> Result<(), Error> {
+    let mut owned = read_owned(dir).ok_or(Error::NotOwned(dir.display().to_string()))?;
+    for name in owned.clone() {
+        if name == OWNED_DIR || keep.contains(&name.as_str()) {
+            continue;
+        }
+        let path = dir.join(&name);
+        match path.symlink_metadata() {
+            Ok(meta) if meta.is_dir() => fs::remove_dir_all(&path),
+            Ok(_) => fs::remove_file(&path),
+            Err(_) => Ok(()),
+        }
+        .set_error(Error::CouldNotWriteFile(path.display().to_string()))?;
+        owned.remove(&name);
+    }
+    owned.retain(|name| name == OWNED_DIR || dir.join(name).symlink_metadata().is_ok());
+    forget(dir, owned)
+}
+
+/// Writes what is still owned, or removes the list and `dir` when the capsule
+/// created it and nothing is left
+fn forget(dir: &Path, owned: BTreeSet<String>) -> Result<(), Error> {
+    if owned.iter().any(|name| name != OWNED_DIR) {
+        return write_owned(dir, &owned);
+    }
+    let path = dir.join(OWNED_FILE);
+    fs::remove_file(&path).set_error(Error::CouldNotWriteFile(path.display().to_string()))?;
+    if owned.contains(OWNED_DIR) {
+        // only succeeds once empty, processes may have left files behind
+        let _ = fs::remove_dir(dir);
+    }
+    Ok(())
+}
+
+fn resolve(cli: Option<PathBuf>) -> Result<PathBuf, Error> {
+    let cwd = env::current_dir().set_error(Error::InternalError)?;
+    let exe = env::current_exe().set_error(Error::InternalError)?;
+    // settings are stored in clear, no password is needed to find the supervisor
+    let configured = || {
+        Payload::open(&exe)
+            .and_then(|mut payload| payload.settings())
+            .ok()
+            .and_then(|settings| settings.data_dir)
+    };
+    pick(
+        &cwd,
+        &exe,
+        cli,
+        env::var_os(DATA_DIR_ENV),
+        configured,
+        default_root(),
+    )
+}
+
+/// The first data directory set: on the command line, in the env, in the
+/// capsule, then under the default root
+fn pick(
+    cwd: &Path,
+    exe: &Path,
+    cli: Option<PathBuf>,
+    from_env: Option<OsString>,
+    configured: impl FnOnce() -> Option<String>,
+    root: Option<PathBuf>,
+) -> Result<PathBuf, Error> {
+    let from_env = from_env.filter(|dir| !dir.is_empty()).map(PathBuf::from);
+    if let Some(dir) = cli.or(from_env) {
+        return Ok(cwd.join(dir));
+    }
+    let exe_dir = exe.parent().ok_or(Error::InternalError)?;
+    if let Some(dir) = configured() {
+        return Ok(exe_dir.join(dir));
+    }
+    Ok(match root {
+        Some(root) => root.join(default_name(exe)?),
+        None => exe_dir.join(".capsule"),
+    })
+}
+
+/// Executable name and the start of the SHA-256 of its canonical path
+fn default_name(exe: &Path) -> Result<OsString, Error> {
+    let path = exe.canonicalize().unwrap_or_else(|_| exe.to_path_buf());
+    let digest = Sha256::digest(path.as_os_str().as_encoded_bytes());
+    let mut name = exe.file_stem().ok_or(Error::InternalError)?.to_os_string();
+    name.push(format!("-{}", hex(&digest[..4])));
+    Ok(name)
+}
+
+#[cfg(unix)]
+fn default_root() -> Option<PathBuf> {
+    // SAFETY: geteuid has no preconditions and can not fail
+    let is_root = unsafe { libc::geteuid() } == 0;
+    state_root(is_root, env::var_os("XDG_STATE_HOME"), env::var_os("HOME"))
+}
+
+#[cfg(unix)]
+fn state_root(is_root: bool, xdg: Option<OsString>, home: Option<OsString>) -> Option<PathBuf> {
+    if is_root {
+        return Some(PathBuf::from("/var/lib"));
+    }
+    let absolute = |dir: PathBuf| dir.is_absolute().then_some(dir);
+    xdg.map(PathBuf::from).and_then(absolute).or_else(|| {
+        home.map(|home| PathBuf::from(home).join(".local").join("state"))
+            .and_then(absolute)
+    })
+}
+
+#[cfg(windows)]
+fn default_root() -> Option<PathBuf> {
+    env::var_os("LOCALAPPDATA").map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OWNED_FILE, check_owned_in, claim_in, default_name, pick, release};
+    use capsules_lib::Error;
+    use capsules_lib::scratch::Scratch;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    /// A directory of the user, `--data-dir ~`
+    fn shared(name: &str) -> Scratch {
+        let dir = Scratch::new(name);
+        dir.write("notes.txt", "mine");
+        dir.write("docs/readme", "mine");
+        dir
+    }
+
+    #[test]
+    fn data_dir_is_resolved_in_order() {
+        let cwd = Path::new("/work");
+        let exe = Path::new("/opt/app/bin/app");
+        let resolve = |cli: Option<&str>, from_env: Option<&str>, configured: Option<&str>| {
+            pick(
+                cwd,
+                exe,
+                cli.map(PathBuf::from),
+                from_env.map(Into::into),
+                || configured.map(String::from),
+                Some(PathBuf::from("/state")),
+            )
+            .unwrap()
+        };
+        let name = default_name(exe).unwrap();
+        assert_eq!(
+            resolve(Some("cli"), Some("env"), Some("conf")),
+            cwd.join("cli")
+        );
+        assert_eq!(
+            resolve(None, Some("/env"), Some("conf")),
+            PathBuf::from("/env")
+        );
+        // an empty env var is unset
+        assert_eq!(
+            resolve(None, Some(""), Some("conf")),
+            PathBuf::from("/opt/app/bin/conf")
+        );
+        assert_eq!(resolve(None, None, None), Path::new("/state").join(&name));
+        let fallback = pick(cwd, exe, None, None, || None, None).unwrap();
+        assert_eq!(fallback, PathBuf::from("/opt/app/bin/.capsule"));
+
+        // copies of a binary do not share state
+        assert!(name.to_string_lossy().starts_with("app-"));
+        assert_ne!(default_name(Path::new("/srv/app")).unwrap(), name);
+        assert_eq!(default_name(exe).unwrap(), name);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn state_root_follows_xdg() {
+        use super::state_root;
+        let root = |is_root, xdg: Option<&str>, home: Option<&str>| {
+            state_root(is_root, xdg.map(Into::into), home.map(Into::into))
+        };
+        let var_lib = Some(PathBuf::from("/var/lib"));
+        assert_eq!(root(true, Some("/xdg"), Some("/home/u")), var_lib);
+        assert_eq!(
+            root(false, Some("/xdg"), Some("/home/u")),
+            Some("/xdg".into())
+        );
+        // relative XDG dirs are ignored
+        let local = Some(PathBuf::from("/home/u/.local/state"));
+        assert_eq!(root(false, Some("xdg"), Some("/home/u")), local);
+        assert_eq!(root(false, None, Some("home")), None);
+        assert_eq!(root(false, None, None), None);
+    }
+
+    #[test]
+    fn only_created_entries_are_cleared() {
+        let dir = shared("data-shared");
+        assert!(matches!(release(&dir, &[]), Err(Error::NotOwned(_))));
+
+        let names = ["docs", "capsule.token", "app"].map(String::from);
+        claim_in(&dir, &names).unwrap();
+        dir.write("capsule.token", "");
+        dir.write("app/data/state", "");
+        release(&dir, &[]).unwrap();
+        assert!(dir.join("notes.txt").exists());
+        assert!(dir.join("docs/readme").exists());
+        assert!(!dir.join("capsule.token").exists());
+        assert!(!dir.join("app").exists());
+        // nothing left to own, the dir was not created by the capsule
+        assert!(!dir.join(OWNED_FILE).exists());
+        assert!(dir.exists());
+    }
+
+    #[test]
+    fn created_dirs_are_removed_once_empty() {
+        let scratch = Scratch::new("data-created");
+        let dir = scratch.join("data");
+        claim_in(&dir, &["logs", "instances"].map(String::from)).unwrap();
+        fs::create_dir_all(dir.join("logs")).unwrap();
+        fs::create_dir_all(dir.join("instances/blue")).unwrap();
+        release(&dir, &["instances"]).unwrap();
+        assert!(!dir.join("logs").exists());
+        assert!(dir.join("instances/blue").exists());
+        fs::remove_dir_all(dir.join("instances")).unwrap();
+        release(&dir, &[]).unwrap();
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn files_not_created_are_not_extracted_over() {
+        let dir = shared("data-extract");
+        claim_in(&dir, &["notes.txt", "docs", "app"].map(String::from)).unwrap();
+        dir.write("app/main.js", "from a previous start");
+
+        let check = |path: &str| check_owned_in(&dir, [path.to_string()]);
+        assert!(matches!(check("notes.txt"), Err(Error::NotOwned(_))));
+        assert!(matches!(check("docs/readme"), Err(Error::NotOwned(_))));
+        // new files are fine, owned ones are replaced
+        assert!(check("docs/new").is_ok());
+        assert!(check("app/main.js").is_ok());
+        assert!(check("config.json").is_ok());
+    }
+}
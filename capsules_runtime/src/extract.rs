@@ -0,0 +1,224 @@
+use capsules_lib::payload::{Blob, Payload};
+use capsules_lib::{Capsule, EmbeddedFile, Error, SetError, check_symlink, safe_relative_path};
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::output::{ExtractedView, Output};
+use crate::{open_capsule, passwords};
+
+/// Writes the files of the capsule under `root`, global files and the ones of
+/// every process, or only the ones of process `only`. Process files land in
+/// `root/<cwd>`, whatever the working directory. Returns the number of files
+pub fn extract_files(
+    payload: &mut Payload<File>,
+    c: &Capsule,
+    root: &Path,
+    only: Option<&str>,
+) -> Result<usize, Error> {
+    let no_blobs = HashMap::new();
+    let blobs = c.blobs.as_ref().unwrap_or(&no_blobs);
+    let mut count = 0;
+
+    if let Some(name) = only
+        && !c.processes.iter().flatten().any(|(n, _)| n == name)
+    {
+        return Err(Error::ProcessNotFound(name.to_string()));
+    }
+    // the root itself may be reached through symlinks, it is picked by the user
+    fs::create_dir_all(root).set_error(Error::CouldNotCreatePath(root.display().to_string()))?;
+
+    if let (None, Some(files)) = (only, &c.embedded) {
+        count += extract_file_map(payload, blobs, root, files)?;
+    }
+    if let Some(processes) = &c.processes {
+        for (name, process) in processes {
+            if only.is_some_and(|only| only != name) {
+                continue;
+            }
+            // the manifest is checked again, it could have been built by hand
+            let cwd = safe_relative_path(process.cwd.as_ref().unwrap_or(name))?;
+            let path = create_dirs(root, &cwd)?;
+            if let Some(files) = &process.embedded {
+                count += extract_file_map(payload, blobs, &path, files)?;
+            }
+        }
+    }
+    Ok(count)
+}
+
+fn extract_file_map(
+    payload: &mut Payload<File>,
+    blobs: &HashMap<String, Blob>,
+    root: &Path,
+    files: &[EmbeddedFile],
+) -> Result<usize, Error> {
+    for file in files {
+        let target = safe_relative_path(&file.target)?;
+        if let Some(link) = &file.symlink {
+            check_symlink(&target, link)?;
+        }
+        let (parent, name) = match target.rsplit_once('/') {
+            Some((parent, name)) => (create_dirs(root, parent)?, name),
+            None => (root.to_path_buf(), target.as_str()),
+        };
+        let out_path = parent.join(name);
+        let could_not_write = || Error::CouldNotWriteFile(out_path.display().to_string());
+        // files from a previous start may be read only or be symlinks
+        if out_path.symlink_metadata().is_ok() {
+            fs::remove_file(&out_path).map_err(|_| could_not_write())?;
+        }
+
+        if let Some(link) = &file.symlink {
+            symlink(link, &out_path).map_err(|_| could_not_write())?;
+            continue;
+        }
+
+        let blob = file
+            .blob
+            .as_ref()
+            .and_then(|blob| blobs.get(blob))
+            .ok_or(Error::CouldNotFindFile(file.target.to_string()))?;
+        let mut out_file = fs::File::create(&out_path)
+            .map_err(|_| Error::CouldNotFindFile(out_path.display().to_string()))?;
+        payload
+            .copy_blob(blob, &mut out_file)
+            .map_err(|_| could_not_write())?;
+        if let Some(mtime) = file.mtime {
+            out_file
+                .set_modified(mtime)
+                .map_err(|_| could_not_write())?;
+        }
+        #[cfg(unix)]
+        if let Some(mode) = file.mode {
+            use std::os::unix::fs::PermissionsExt;
+            out_file
+                .set_permissions(fs::Permissions::from_mode(mode))
+                .map_err(|_| could_not_write())?;
+        }
+    }
+    Ok(files.len())
+}
+
+/// Creates the directories of `relative` under `root` without going through
+/// symlinks. Each link is checked on its own, a link extracted earlier could
+/// still move the next ones outside of `root`, `t -> .` then `t/up -> ..`
+fn create_dirs(root: &Path, relative: &str) -> Result<PathBuf, Error> {
+    let mut path = root.to_path_buf();
+    for segment in relative.split('/') {
+        path.push(segment);
+        match path.symlink_metadata() {
+            Ok(meta) if meta.is_symlink() => return Err(Error::UnsafePath(relative.to_string())),
+            Ok(meta) if meta.is_dir() => {}
+            Ok(_) => return Err(Error::CouldNotCreatePath(path.display().to_string())),
+            Err(_) => fs::create_dir(&path)
+                .set_error(Error::CouldNotCreatePath(path.display().to_string()))?,
+        }
+    }
+    Ok(path)
+}
+
+#[cfg(unix)]
+fn symlink(link: &str, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(link, path)
+}
+
+#[cfg(windows)]
+fn symlink(link: &str, path: &Path) -> io::Result<()> {
+    let link = link.replace('/', "\\");
+    match path.parent().map(|p| p.join(&link).is_dir()) {
+        Some(true) => std::os::windows::fs::symlink_dir(link, path),
+        _ => std::os::windows::fs::symlink_file(link, path),
+    }
+}
+
+/// Extraction for audits, no supervisor, socket or process is involved
+pub fn cli_extract(out: Output, to: Option<PathBuf>, only: Option<String>) -> Result<(), Error> {
+    let cwd = env::current_dir().set_error(Error::InternalError)?;
+    let root = cwd.join(to.unwrap_or_default());
+    let (mut payload, capsule) = open_capsule(passwords::payload)?;
+    let files = extract_files(&mut payload, &capsule, &root, only.as_deref())?;
+    let view = ExtractedView {
+        to: root.display().to_string(),
+        files,
+    };
+    out.print(&view, || {
+        println!("Extracted {} files to {}", view.files, view.to)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::extract_files;
+    use capsules_lib::payload::{Payload, PayloadWriter};
+    use capsules_lib::scratch::Scratch;
+    use capsules_lib::{Capsule, EmbeddedFile, Error};
+    use std::fs::{self, File};
+    use std::io::Cursor;
+    use std::path::Path;
+
+    /// Writes a capsule embedding `files`, `(target, symlink)` pairs, to `dir/capsule`
+    fn build(dir: &Path, files: &[(&str, Option<&str>)]) -> (Payload<File>, Capsule) {
+        let path = dir.join("capsule");
+        let mut writer = PayloadWriter::new(File::create(&path).unwrap(), b"", None).unwrap();
+        let mut capsule: Capsule = serde_json::from_str(r#"{"version": "1.0.0"}"#).unwrap();
+        let embedded = files
+            .iter()
+            .map(|(target, link)| EmbeddedFile {
+                target: target.to_string(),
+                blob: match link {
+                    Some(_) => None,
+                    None => Some(writer.add(&mut Cursor::new(b"pwned")).unwrap()),
+                },
+                symlink: link.map(str::to_string),
+                mode: None,
+                mtime: None,
+            })
+            .collect();
+        capsule.embedded = Some(embedded);
+        writer.finish(capsule, None).unwrap();
+        let mut payload = Payload::open(&path).unwrap();
+        let capsule = payload.manifest().unwrap();
+        (payload, capsule)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn chained_symlinks_do_not_escape() {
+        let dir = Scratch::new("extract-chained");
+        // each link stays inside on its own, t/esc is root/esc once t is a link
+        let (mut payload, capsule) = build(
+            &dir,
+            &[
+                ("t", Some(".")),
+                ("t/esc", Some("../outside")),
+                ("t/esc/pwned", None),
+            ],
+        );
+        let root = dir.join("root/inner");
+        assert!(matches!(
+            extract_files(&mut payload, &capsule, &root, None),
+            Err(Error::UnsafePath(_))
+        ));
+        assert!(!dir.join("root/outside").exists());
+        assert!(!root.join("esc").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn files_replace_previous_symlinks() {
+        let dir = Scratch::new("extract-replace");
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("lib")).unwrap();
+        std::os::unix::fs::symlink("../../elsewhere", root.join("lib/app.js")).unwrap();
+        let (mut payload, capsule) = build(&dir, &[("lib/app.js", None), ("current", Some("lib"))]);
+        assert_eq!(
+            extract_files(&mut payload, &capsule, &root, None).unwrap(),
+            2
+        );
+        assert_eq!(fs::read(root.join("lib/app.js")).unwrap(), b"pwned");
+        assert_eq!(fs::read(root.join("current/app.js")).unwrap(), b"pwned");
+    }
+}
@@ -0,0 +1,242 @@
+use capsules_lib::{Probe, Process, RunningProcess, Status};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{process_cwd, process_env, secrets};
+
+const DEFAULT_INTERVAL: u64 = 10_000;
+const DEFAULT_TIMEOUT: u64 = 5_000;
+const DEFAULT_RETRIES: u32 = 3;
+
+/// Exec probes run with the env and the secrets of the process `name`
+fn probe(probe: &Probe, name: &str, config: &Process, cwd: &Path, timeout: Duration) -> bool {
+    match probe {
+        Probe::Exec { cmd, args } => {
+            let mut command = Command::new(cmd);
+            command
+                .args(args.clone().unwrap_or_default())
+                .current_dir(cwd)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null());
+            process_env::apply(&mut command, config);
+            secrets::apply(&mut command, name);
+            let Ok(mut child) = command.spawn() else {
+                return false;
+            };
+            let started = Instant::now();
+            loop {
+                match child.try_wait() {
+                    Ok(Some(status)) => return status.success(),
+                    Ok(None) if started.elapsed() < timeout => {
+                        thread::sleep(Duration::from_millis(10))
+                    }
+                    _ => {
+                        child.kill().ok();
+                        child.wait().ok();
+                        return false;
+                    }
+                }
+            }
+        }
+        Probe::Tcp { port } => {
+            TcpStream::connect_timeout(&SocketAddr::from(([127, 0, 0, 1], *port)), timeout).is_ok()
+        }
+        Probe::Http { port, path } => {
+            let addr = SocketAddr::from(([127, 0, 0, 1], *port));
+            let Ok(mut stream) = TcpStream::connect_timeout(&addr, timeout) else {
+                return false;
+            };
+            stream.set_read_timeout(Some(timeout)).ok();
+            stream.set_write_timeout(Some(timeout)).ok();
+            let request = format!(
+                "GET {} HTTP/1.0\r\nHost: localhost:{}\r\nConnection: close\r\n\r\n",
+                path.as_deref().unwrap_or("/"),
+                port
+            );
+            if stream.write_all(request.as_bytes()).is_err() {
+                return false;
+            }
+            let mut status_line = String::new();
+            if BufReader::new(stream).read_line(&mut status_line).is_err() {
+                return false;
+            }
+            healthy_status(&status_line)
+        }
+    }
+}
+
+/// `HTTP/1.1 200 OK`, healthy with a 2xx or 3xx
+fn healthy_status(status_line: &str) -> bool {
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .is_some_and(|code| (200..400).contains(&code))
+}
+
+/// Collects the result of the last probe and schedules the next one.
+/// Returns true when the process just became unhealthy and should be restarted
+pub fn check(proc: &mut RunningProcess) -> bool {
+    let Some(config) = &proc.config.health_check else {
+        return false;
+    };
+    let Some(pid) = proc.status.pid() else {
+        return false;
+    };
+    let health = &mut proc.health;
+
+    if let Some(handle) = health.in_flight.take_if(|h| h.is_finished()) {
+        if handle.join().unwrap_or(false) {
+            health.healthy = true;
+            health.failures = 0;
+            proc.status = Status::Running(pid);
+        } else if proc.started.elapsed() >= Duration::from_millis(config.start_period.unwrap_or(0))
+        {
+            health.failures += 1;
+            if health.failures >= config.retries.unwrap_or(DEFAULT_RETRIES)
+                && proc.status != Status::Unhealthy(pid)
+            {
+                proc.status = Status::Unhealthy(pid);
+                return config.restart_on_unhealthy.unwrap_or(false);
+            }
+        }
+    }
+
+    let interval = Duration::from_millis(config.interval.unwrap_or(DEFAULT_INTERVAL));
+    if health.in_flight.is_none() && health.last_probe.is_none_or(|t| t.elapsed() >= interval) {
+        let Ok(cwd) = process_cwd(&proc.name, &proc.config) else {
+            return false;
+        };
+        let target = config.probe.clone();
+        let timeout = Duration::from_millis(config.timeout.unwrap_or(DEFAULT_TIMEOUT));
+        let (name, process) = (proc.name.clone(), proc.config.clone());
+        health.last_probe = Some(Instant::now());
+        health.in_flight = Some(thread::spawn(move || {
+            probe(&target, &name, &process, &cwd, timeout)
+        }));
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{healthy_status, probe};
+    use capsules_lib::{Probe, Process};
+    use std::env;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    fn config(env: serde_json::Value) -> Process {
+        serde_json::from_value(serde_json::json!({"cmd": "app", "env": env})).unwrap()
+    }
+
+    /// Answers one request with `status_line`
+    fn serve(status_line: &'static str) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request_line = String::new();
+            BufReader::new(&stream).read_line(&mut request_line).ok();
+            write!(stream, "{}\r\nContent-Length: 0\r\n\r\n", status_line).ok();
+        });
+        port
+    }
+
+    #[test]
+    fn http_status_maps_to_health() {
+        for line in [
+            "HTTP/1.1 200 OK",
+            "HTTP/1.0 204 No Content",
+            "HTTP/1.1 302 Found",
+        ] {
+            assert!(healthy_status(line), "{line:?} should be healthy");
+        }
+        for line in [
+            "HTTP/1.1 199 Early",
+            "HTTP/1.1 404 Not Found",
+            "HTTP/1.1 503 Service Unavailable",
+            "HTTP/1.1 OK",
+            "SSH-2.0-OpenSSH",
+            "",
+        ] {
+            assert!(!healthy_status(line), "{line:?} should be unhealthy");
+        }
+    }
+
+    #[test]
+    fn http_probe_reads_the_status() {
+        let cwd = env::temp_dir();
+        let app = config(serde_json::json!({}));
+        let healthy = Probe::Http {
+            port: serve("HTTP/1.1 200 OK"),
+            path: Some("/health".to_string()),
+        };
+        assert!(probe(&healthy, "app", &app, &cwd, TIMEOUT));
+        let failing = Probe::Http {
+            port: serve("HTTP/1.1 500 Internal Server Error"),
+            path: None,
+        };
+        assert!(!probe(&failing, "app", &app, &cwd, TIMEOUT));
+    }
+
+    #[test]
+    fn tcp_probe_connects() {
+        let cwd = env::temp_dir();
+        let app = config(serde_json::json!({}));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(probe(&Probe::Tcp { port }, "app", &app, &cwd, TIMEOUT));
+        drop(listener);
+        assert!(!probe(&Probe::Tcp { port }, "app", &app, &cwd, TIMEOUT));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exec_probe_checks_the_exit_code() {
+        let cwd = env::temp_dir();
+        let app = config(serde_json::json!({}));
+        let exec = |cmd: &str, args: &[&str]| Probe::Exec {
+            cmd: cmd.to_string(),
+            args: Some(args.iter().map(|a| a.to_string()).collect()),
+        };
+        assert!(probe(&exec("true", &[]), "app", &app, &cwd, TIMEOUT));
+        assert!(!probe(&exec("false", &[]), "app", &app, &cwd, TIMEOUT));
+        assert!(!probe(
+            &exec("/nonexistent/probe", &[]),
+            "app",
+            &app,
+            &cwd,
+            TIMEOUT
+        ));
+        // killed once the timeout is over
+        let slow = exec("sleep", &["5"]);
+        assert!(!probe(&slow, "app", &app, &cwd, Duration::from_millis(100)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exec_probe_gets_the_process_env() {
+        let cwd = env::temp_dir();
+        let app = config(serde_json::json!({"DB_HOST": "db.internal"}));
+        let check = Probe::Exec {
+            cmd: "sh".to_string(),
+            args: Some(vec![
+                "-c".to_string(),
+                r#"test "$DB_HOST" = db.internal"#.to_string(),
+            ]),
+        };
+        assert!(probe(&check, "app", &app, &cwd, TIMEOUT));
+        let other = config(serde_json::json!({}));
+        assert!(!probe(&check, "app", &other, &cwd, TIMEOUT));
+    }
+}
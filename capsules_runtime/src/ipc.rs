@@ -0,0 +1,219 @@
+//! Control channel between the CLI and the supervisor.
+//!
+//! Unix domain socket on unix, named pipe on Windows. Every frame is a
+//! little endian u32 length followed by a postcard payload. Requests carry the
+//! token the supervisor writes to `capsule.token` when it starts.
+
+use capsules_lib::{CliMessage, CliRequest, Error, Exitable, SetError, SupervisorResp};
+use postcard::{from_bytes, to_allocvec};
+use rand::RngCore;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use sys::{Stream, connect};
+pub use sys::{accept, bind};
+
+/// Requests are tiny, anything bigger is not coming from our CLI
+const MAX_REQUEST_SIZE: u32 = 1024 * 1024;
+const TOKEN_SIZE: usize = 32;
+/// How long the supervisor waits for a request once a client connected
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[cfg(unix)]
+mod sys {
+    use std::fs::{self, Permissions};
+    use std::io;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::Path;
+    use std::time::Duration;
+
+    use super::REQUEST_TIMEOUT;
+
+    pub type Listener = UnixListener;
+    pub type Stream = UnixStream;
+
+    pub fn bind(path: &Path) -> io::Result<Listener> {
+        // left over by a supervisor that did not exit cleanly
+        fs::remove_file(path).ok();
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, Permissions::from_mode(0o600))?;
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+    }
+
+    pub fn accept(listener: &Listener) -> io::Result<Stream> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        Ok(stream)
+    }
+
+    pub fn connect(path: &Path, timeout: Duration) -> io::Result<Stream> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(stream)
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use interprocess::local_socket::{
+        GenericNamespaced, ListenerNonblockingMode, ListenerOptions, Name, prelude::*,
+    };
+    use std::hash::{DefaultHasher, Hash, Hasher};
+    use std::io::{self, Read, Write};
+    use std::path::Path;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::REQUEST_TIMEOUT;
+
+    pub type Listener = LocalSocketListener;
+
+    /// Named pipes have no read timeout, the pipe is non-blocking and reads
+    /// and writes are retried until `timeout` runs out
+    pub struct Stream {
+        pipe: LocalSocketStream,
+        timeout: Duration,
+    }
+
+    impl Stream {
+        fn new(pipe: LocalSocketStream, timeout: Duration) -> io::Result<Self> {
+            pipe.set_nonblocking(true)?;
+            Ok(Stream { pipe, timeout })
+        }
+
+        fn retry<T>(
+            &mut self,
+            mut f: impl FnMut(&mut LocalSocketStream) -> io::Result<T>,
+        ) -> io::Result<T> {
+            let started = Instant::now();
+            loop {
+                match f(&mut self.pipe) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        if started.elapsed() >= self.timeout {
+                            return Err(io::ErrorKind::TimedOut.into());
+                        }
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                    result => return result,
+                }
+            }
+        }
+    }
+
+    impl Read for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.retry(|pipe| pipe.read(buf))
+        }
+    }
+
+    impl Write for Stream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.retry(|pipe| pipe.write(buf))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.retry(|pipe| pipe.flush())
+        }
+    }
+
+    /// Named pipes live in their own namespace, the name is derived from the socket path
+    fn pipe_name(path: &Path) -> io::Result<Name<'static>> {
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        format!("capsule-{:016x}", hasher.finish()).to_ns_name::<GenericNamespaced>()
+    }
+
+    pub fn bind(path: &Path) -> io::Result<Listener> {
+        ListenerOptions::new()
+            .name(pipe_name(path)?)
+            .nonblocking(ListenerNonblockingMode::Accept)
+            .create_sync()
+    }
+
+    pub fn accept(listener: &Listener) -> io::Result<Stream> {
+        Stream::new(listener.accept()?, REQUEST_TIMEOUT)
+    }
+
+    pub fn connect(path: &Path, timeout: Duration) -> io::Result<Stream> {
+        Stream::new(LocalSocketStream::connect(pipe_name(path)?)?, timeout)
+    }
+}
+
+/// Creates a new token, only readable by the current user
+pub fn write_token(path: &Path) -> Result<Vec<u8>, Error> {
+    let mut token = vec![0u8; TOKEN_SIZE];
+    rand::rng().fill_bytes(&mut token);
+    fs::remove_file(path).ok();
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(path)
+        .and_then(|mut f| f.write_all(&token))
+        .set_error(Error::CouldNotWriteFile(path.display().to_string()))?;
+    Ok(token)
+}
+
+fn write_frame(stream: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(data.len()).map_err(|_| io::ErrorKind::InvalidInput)?;
+    stream.write_all(&len.to_le_bytes())?;
+    stream.write_all(data)?;
+    stream.flush()
+}
+
+fn read_frame(stream: &mut impl Read, max: u32) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len);
+    if len > max {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    let mut data = vec![0u8; len as usize];
+    stream.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn same_token(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Reads a request, answers with `Error::Unauthorized` when the token does not match
+pub fn read_request(stream: &mut Stream, token: &[u8]) -> Option<CliMessage> {
+    let data = read_frame(stream, MAX_REQUEST_SIZE).ok()?;
+    let req: CliRequest = from_bytes(&data).ok()?;
+    if !same_token(&req.token, token) {
+        respond(stream, &SupervisorResp::Error(Error::Unauthorized)).log();
+        return None;
+    }
+    Some(req.message)
+}
+
+pub fn respond(stream: &mut Stream, resp: &SupervisorResp) -> Result<(), Error> {
+    let data = to_allocvec(resp).set_error(Error::InternalError)?;
+    write_frame(stream, &data).set_error(Error::InternalError)
+}
+
+/// Sends a request and waits up to `timeout` for the answer
+pub fn send(
+    socket: &Path,
+    token: &Path,
+    message: CliMessage,
+    timeout: Duration,
+) -> Result<SupervisorResp, Error> {
+    let token = fs::read(token).set_error(Error::SupervisorCantBeFound)?;
+    let mut stream = connect(socket, timeout).set_error(Error::SupervisorCantBeFound)?;
+    let req = to_allocvec(&CliRequest { token, message }).set_error(Error::InternalError)?;
+    write_frame(&mut stream, &req).set_error(Error::SupervisorCantBeFound)?;
+    let resp = read_frame(&mut stream, u32::MAX).set_error(Error::SupervisorCantBeFound)?;
+    from_bytes(&resp).set_error(Error::InternalError)
+}
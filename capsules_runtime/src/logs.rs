@@ -0,0 +1,331 @@
+use capsules_lib::{
+    DEFAULT_LOG_MAX_FILES, DEFAULT_LOG_MAX_SIZE, Error, LogConfig, SetError, check_process_name,
+};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use crate::get_capsule_cwd;
+use crate::output::{LogsView, Output};
+
+pub fn log_dir(name: &str) -> Result<PathBuf, Error> {
+    check_process_name(name)?;
+    Ok(get_capsule_cwd()?.join("logs").join(name))
+}
+
+fn stream_name(stderr: bool) -> &'static str {
+    if stderr { "stderr" } else { "stdout" }
+}
+
+/// Size rotated log file
+/// `<stream>.log` is the live file, `<stream>.log.1` is the most recent rotated one
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: u32,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, config: Option<&LogConfig>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            max_size: config
+                .and_then(|c| c.max_size)
+                .unwrap_or(DEFAULT_LOG_MAX_SIZE)
+                .max(1),
+            max_files: config
+                .and_then(|c| c.max_files)
+                .unwrap_or(DEFAULT_LOG_MAX_FILES),
+        })
+    }
+
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            self.file.set_len(0)?;
+            self.size = 0;
+            return Ok(());
+        }
+        fs::remove_file(self.rotated(self.max_files)).ok();
+        for n in (1..self.max_files).rev() {
+            fs::rename(self.rotated(n), self.rotated(n + 1)).ok();
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let len = self.file.write(buf)?;
+        self.size += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+type SharedFile = Arc<Mutex<RotatingFile>>;
+
+/// One writer per log file, shared by the runs of a process and the notes of
+/// the supervisor so that they agree on its size and rotate it together
+static WRITERS: OnceLock<Mutex<HashMap<PathBuf, SharedFile>>> = OnceLock::new();
+
+fn writer(path: PathBuf, config: Option<&LogConfig>) -> Result<SharedFile, Error> {
+    let mut writers = WRITERS
+        .get_or_init(Default::default)
+        .lock()
+        .map_err(|_| Error::InternalError)?;
+    if let Some(writer) = writers.get(&path) {
+        return Ok(Arc::clone(writer));
+    }
+    let file = RotatingFile::open(path.clone(), config)
+        .set_error(Error::CouldNotWriteFile(path.display().to_string()))?;
+    let writer = Arc::new(Mutex::new(file));
+    writers.insert(path, Arc::clone(&writer));
+    Ok(writer)
+}
+
+/// Pipes the child's stdout and stderr into `logs/<name>/`
+/// the spawned threads exit once the child closes its end of the pipe
+pub fn capture(name: &str, child: &mut Child, config: Option<&LogConfig>) -> Result<(), Error> {
+    let dir = log_dir(name)?;
+    fs::create_dir_all(&dir).set_error(Error::CouldNotCreatePath(dir.display().to_string()))?;
+
+    fn pipe(
+        mut src: impl Read + Send + 'static,
+        path: PathBuf,
+        config: Option<&LogConfig>,
+    ) -> Result<(), Error> {
+        let out = writer(path, config)?;
+        thread::spawn(move || {
+            let mut buf = [0u8; 8192];
+            // locked per chunk, other writers get their turn
+            while let Ok(len) = src.read(&mut buf)
+                && len > 0
+                && let Ok(mut out) = out.lock()
+                && out.write_all(&buf[..len]).is_ok()
+            {}
+        });
+        Ok(())
+    }
+
+    if let Some(stdout) = child.stdout.take() {
+        pipe(stdout, dir.join("stdout.log"), config)?;
+    }
+    if let Some(stderr) = child.stderr.take() {
+        pipe(stderr, dir.join("stderr.log"), config)?;
+    }
+    Ok(())
+}
+
+/// Appends a line from the supervisor to the stderr log of the process, its
+/// own stderr goes nowhere once daemonized
+pub fn note(name: &str, config: Option<&LogConfig>, message: &str) -> Result<(), Error> {
+    let dir = log_dir(name)?;
+    fs::create_dir_all(&dir).set_error(Error::CouldNotCreatePath(dir.display().to_string()))?;
+    let path = dir.join("stderr.log");
+    let writer = writer(path.clone(), config)?;
+    let mut file = writer.lock().map_err(|_| Error::InternalError)?;
+    file.write_all(format!("[capsule] {}\n", message).as_bytes())
+        .set_error(Error::CouldNotWriteFile(path.display().to_string()))
+}
+
+/// Returns the log files of a stream, oldest first
+fn log_files(dir: &Path, stderr: bool) -> Vec<PathBuf> {
+    let live = dir.join(format!("{}.log", stream_name(stderr)));
+    let mut files = vec![];
+    let mut n = 1;
+    loop {
+        let mut rotated = live.clone().into_os_string();
+        rotated.push(format!(".{}", n));
+        let rotated = PathBuf::from(rotated);
+        if !rotated.exists() {
+            break;
+        }
+        files.push(rotated);
+        n += 1;
+    }
+    files.reverse();
+    files.push(live);
+    files
+}
+
+pub fn cli_proc_logs(
+    out: Output,
+    name: String,
+    follow: bool,
+    lines: usize,
+    stderr: bool,
+) -> Result<(), Error> {
+    if follow && out != Output::Text {
+        return Err(Error::UnsupportedOutput("proc logs --follow".to_string()));
+    }
+    let dir = log_dir(&name)?;
+    if !dir.exists() {
+        return Err(Error::ProcessNotFound(name));
+    }
+    let files = log_files(&dir, stderr);
+
+    let mut tail: Vec<String> = vec![];
+    for path in files.iter().rev() {
+        if tail.len() >= lines {
+            break;
+        }
+        let Ok(content) = fs::read(path) else {
+            continue;
+        };
+        let content = String::from_utf8_lossy(&content);
+        let mut chunk: Vec<String> = content.lines().map(str::to_string).collect();
+        let keep = chunk.len().saturating_sub(lines - tail.len());
+        chunk.drain(..keep);
+        chunk.append(&mut tail);
+        tail = chunk;
+    }
+    let view = LogsView {
+        name: &name,
+        stream: stream_name(stderr),
+        lines: &tail,
+    };
+    out.print(&view, || {
+        for line in &tail {
+            println!("{}", line);
+        }
+    })?;
+
+    if !follow {
+        return Ok(());
+    }
+
+    let live = files.last().ok_or(Error::InternalError)?.clone();
+    let mut offset = fs::metadata(&live).map(|m| m.len()).unwrap_or(0);
+    let mut stdout = io::stdout();
+    loop {
+        thread::sleep(Duration::from_millis(200));
+        let Ok(mut file) = File::open(&live) else {
+            continue;
+        };
+        let len = file.metadata().map(|m| m.len()).unwrap_or(0);
+        if len < offset {
+            // the file was rotated
+            offset = 0;
+        }
+        if len == offset {
+            continue;
+        }
+        file.seek(SeekFrom::Start(offset))
+            .set_error(Error::CouldNotReadFile(live.display().to_string()))?;
+        offset += io::copy(&mut file.take(len - offset), &mut stdout)
+            .set_error(Error::CouldNotReadFile(live.display().to_string()))?;
+        stdout.flush().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RotatingFile, writer};
+    use capsules_lib::LogConfig;
+    use capsules_lib::scratch::Scratch;
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+
+    fn config(max_size: u64, max_files: u32) -> LogConfig {
+        LogConfig {
+            max_size: Some(max_size),
+            max_files: Some(max_files),
+        }
+    }
+
+    fn read(path: &Path) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn files_are_rotated_by_size() {
+        let dir = Scratch::new("logs-rotate");
+        let path = dir.join("stdout.log");
+        let config = config(10, 2);
+        let mut file = RotatingFile::open(path.clone(), Some(&config)).unwrap();
+        for chunk in ["0123456789", "abcd", "efghijklmn", "op"] {
+            file.write_all(chunk.as_bytes()).unwrap();
+        }
+
+        assert_eq!(read(&path), "op");
+        assert_eq!(read(&file.rotated(1)), "efghijklmn");
+        assert_eq!(read(&file.rotated(2)), "abcd");
+        // the oldest file is dropped past max_files
+        assert!(!file.rotated(3).exists());
+    }
+
+    #[test]
+    fn reopened_files_keep_their_size() {
+        let dir = Scratch::new("logs-reopen");
+        let path = dir.write("stdout.log", "01234567");
+        let config = config(10, 1);
+        let mut file = RotatingFile::open(path.clone(), Some(&config)).unwrap();
+        file.write_all(b"89ab").unwrap();
+
+        assert_eq!(read(&path), "89ab");
+        assert_eq!(read(&file.rotated(1)), "01234567");
+    }
+
+    #[test]
+    fn no_retention_truncates_in_place() {
+        let dir = Scratch::new("logs-truncate");
+        let path = dir.join("stderr.log");
+        let config = config(4, 0);
+        let mut file = RotatingFile::open(path.clone(), Some(&config)).unwrap();
+        file.write_all(b"abcd").unwrap();
+        file.write_all(b"ef").unwrap();
+
+        assert_eq!(read(&path), "ef");
+        assert!(!file.rotated(1).exists());
+    }
+
+    #[test]
+    fn writers_of_a_file_are_shared() {
+        let dir = Scratch::new("logs-shared");
+        let path = dir.join("stderr.log");
+        let config = config(10, 1);
+        // the capture thread and a note of the supervisor
+        let capture = writer(path.clone(), Some(&config)).unwrap();
+        let note = writer(path.clone(), Some(&config)).unwrap();
+        capture.lock().unwrap().write_all(b"01234567").unwrap();
+        // rotated by the note, then by the capture
+        note.lock().unwrap().write_all(b"[capsule]\n").unwrap();
+        capture.lock().unwrap().write_all(b"89").unwrap();
+
+        assert_eq!(read(&path), "89");
+        let rotated = note.lock().unwrap().rotated(1);
+        assert_eq!(read(&rotated), "[capsule]\n");
+        note.lock().unwrap().write_all(b"ab").unwrap();
+        assert_eq!(read(&path), "89ab");
+    }
+}
@@ -1,391 +1,676 @@
-use atty::Stream;
+mod data_dir;
+mod extract;
+mod health;
+mod ipc;
+mod logs;
+mod output;
+mod passwords;
+mod process_env;
+mod secrets;
+mod stop;
+mod trust;
+
+use capsules_lib::env::parse_dotenv;
+use capsules_lib::info::CapsuleInfo;
+use capsules_lib::payload::{Payload, Scheme, hex};
 use capsules_lib::{
-    ASCII_ART, Capsule, CliMessage, Env, Error, Exitable, ExitableError, FOOTER_SIZE, ListResp,
-    MAGIC_NUMBER_ENCRYPTED, MAGIC_NUMBER_PLAIN, Process, RestartPolicy, RunningProcess, SetError,
-    Status, SupervisorResp, Table, decrypt,
+    ASCII_ART, Capsule, CliMessage, DEFAULT_BACKOFF_MAX, DependencyCondition, Env, Error, Exitable,
+    HealthState, ListResp, ProcStatusResp, Process, RestartPolicy, RunningProcess, SetError,
+    Status, SupervisorResp, Table, start_order,
 };
 use clap::{Parser, Subcommand};
-use postcard::{from_bytes, to_allocvec};
-use rpassword::{prompt_password, read_password_from_bufread};
+use output::{InstanceView, OkView, Output, ProcStatusView, ProcessView, StoppedView, VersionView};
+use passwords::KeyArgs;
 use std::collections::HashMap;
 use std::env;
 use std::fs::{self, File};
-use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
-use std::net::UdpSocket;
+use std::io::{self, BufRead, Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Child, Command, Stdio};
-use std::time::{Duration, Instant};
+use std::process::{self, Child, Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use sysinfo::{Pid, System, get_current_pid};
-use zip::ZipArchive;
-
-fn get_data() -> Result<Vec<u8>, Error> {
-    let exe_path = env::current_exe().map_err(|_| Error::NoData)?;
-    let mut file = File::open(exe_path).map_err(|_| Error::NoData)?;
-    file.seek(SeekFrom::End(-FOOTER_SIZE))
-        .map_err(|_| Error::NoData)?;
-    let mut footer_bytes = [0u8; FOOTER_SIZE as usize];
-    file.read_exact(&mut footer_bytes)
-        .map_err(|_| Error::NoData)?;
-    let magic = &footer_bytes[8..16];
-
-    if magic != MAGIC_NUMBER_PLAIN && magic != MAGIC_NUMBER_ENCRYPTED {
-        return Err(Error::NoData);
-    }
+use trust::TrustArgs;
 
-    let len_bytes: [u8; 8] = footer_bytes[0..8].try_into().map_err(|_| Error::NoData)?;
-    let data_len = u64::from_le_bytes(len_bytes);
-    let data_start_offset = -FOOTER_SIZE - (data_len as i64);
-    file.seek(SeekFrom::End(data_start_offset))
-        .map_err(|_| Error::NoData)?;
-    let mut data = vec![0u8; data_len as usize];
-    file.read_exact(&mut data).map_err(|_| Error::NoData)?;
+/// Env var older versions handed the password to the supervisor in, scrubbed
+/// from the supervisor and its processes in case it is still set
+pub(crate) const PASSWORD_ENV: &str = "__SUPERVISOR_PASSWORD__";
 
-    if magic == MAGIC_NUMBER_PLAIN {
-        return Ok(data);
+/// Opens the payload of the current executable and reads its manifest, file
+/// contents are read later on by `extract_files`. Returns the password of the
+/// secrets as well when they have their own. `daemon start` writes the
+/// passwords to the stdin of the supervisor, one per line, and passes the
+/// digest of the executable it checked
+fn get_data(digest: Option<&str>) -> Result<(Payload<File>, Capsule, Option<String>), Error> {
+    let payload = open_payload()?;
+    if digest.is_some_and(|digest| digest != hex(payload.digest())) {
+        return Err(Error::IntegrityCheckFailed);
     }
-    if data.len() < 28 {
-        return Err(Error::InvalidDataFormat);
-    }
-    let password = env::var("__SUPERVISOR_PASSWORD__").map_err(|_| Error::InvalidPassword)?;
-
-    let salt = &data[0..16];
-    let nonce = &data[16..28];
-    let ciphertext = &data[28..];
-    decrypt(&password, salt, nonce, ciphertext)
+    let (mut payload, capsule) = read_manifest(payload, passwords::payload)?;
+    let secrets_password = match payload.settings()?.secrets_password {
+        true => Some(passwords::secrets()?),
+        false => None,
+    };
+    Ok((payload, capsule, secrets_password))
 }
 
-fn read_password() -> Result<String, Error> {
-    if !atty::is(Stream::Stdin) {
-        let mut reader = BufReader::new(io::stdin());
-        return read_password_from_bufread(&mut reader)
-            .map(|e| e.trim().to_string())
-            .set_error(Error::InternalError);
-    }
-    prompt_password("Enter password: ").set_error(Error::InternalError)
+/// Env overlay of `daemon start --env-file`, written as a json line after the passwords
+fn read_env_overlay() -> Result<Env, Error> {
+    let mut line = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .set_error(Error::InternalError)?;
+    serde_json::from_str(&line).set_error(Error::InternalError)
 }
 
-fn extract_files(mut c: Capsule) -> Result<Capsule, Error> {
-    let fs_bytes = match &c.fs {
-        Some(bytes) => bytes,
-        None => return Ok(c),
-    };
+/// Opens the payload of the current executable, once its digest and its
+/// signature check out
+fn open_payload() -> Result<Payload<File>, Error> {
+    let exe_path = env::current_exe().map_err(|_| Error::NoData)?;
+    let mut payload = Payload::open(&exe_path)?;
+    trust::check(&mut payload)?;
+    Ok(payload)
+}
 
-    let root: PathBuf = get_capsule_cwd()?;
-    let cursor = Cursor::new(fs_bytes);
-    let mut zip = ZipArchive::new(cursor).map_err(|_| Error::InternalError)?;
+/// `password` is only asked for when the payload is encrypted, it gets the
+/// scheme to prompt for a password or a key
+pub(crate) fn open_capsule(
+    password: impl FnOnce(&Scheme) -> Result<String, Error>,
+) -> Result<(Payload<File>, Capsule), Error> {
+    read_manifest(open_payload()?, password)
+}
 
-    if let Some(files) = &c.files {
-        extract_file_map(&mut zip, &root, files)?;
-    }
-    if let Some(processes) = &c.processes {
-        for (name, process) in processes {
-            let cwd = process.cwd.as_ref().unwrap_or(name);
-            let path = root.join(cwd);
-            fs::create_dir_all(&path)
-                .set_error(Error::CouldNotCreatePath(path.display().to_string()))?;
-            if let Some(files) = &process.files {
-                extract_file_map(&mut zip, Path::new(cwd), files)?;
-            }
-        }
+fn read_manifest(
+    mut payload: Payload<File>,
+    password: impl FnOnce(&Scheme) -> Result<String, Error>,
+) -> Result<(Payload<File>, Capsule), Error> {
+    if payload.is_encrypted() {
+        let secret = password(payload.scheme())?;
+        payload.unlock(&secret)?;
     }
-    c.fs.take();
-    Ok(c)
+    let capsule = payload.manifest()?;
+    Ok((payload, capsule))
 }
 
-fn clear_files() -> Result<(), Error> {
-    let root = get_capsule_cwd()?;
-    fs::remove_dir_all(&root).set_error(Error::InternalError)?;
-    Ok(())
+pub(crate) fn get_capsule_cwd() -> Result<PathBuf, Error> {
+    data_dir::get()
 }
 
-fn extract_file_map(
-    zip: &mut ZipArchive<Cursor<&Vec<u8>>>,
-    root: &Path,
-    files: &HashMap<String, String>,
-) -> Result<(), Error> {
-    for (zip_name, target_path) in files {
-        let mut file = zip
-            .by_name(zip_name)
-            .map_err(|_| Error::CouldNotFindFile(target_path.to_string()))?;
-
-        let out_path = root.join(target_path);
+pub(crate) fn process_cwd(name: &str, proc: &Process) -> Result<PathBuf, Error> {
+    Ok(get_capsule_cwd()?.join(proc.cwd.as_deref().unwrap_or(name)))
+}
 
-        if let Some(parent) = out_path.parent() {
-            fs::create_dir_all(parent)
-                .map_err(|_| Error::CouldNotFindFile(parent.display().to_string()))?;
-        }
+fn unix_secs(t: SystemTime) -> Option<u64> {
+    t.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
 
-        let mut out_file = fs::File::create(&out_path)
-            .map_err(|_| Error::CouldNotFindFile(out_path.display().to_string()))?;
-        std::io::copy(&mut file, &mut out_file)
-            .map_err(|_| Error::CouldNotWriteFile(out_path.display().to_string()))?;
-    }
-    Ok(())
+fn get_socket_path() -> Result<PathBuf, Error> {
+    Ok(get_capsule_cwd()?.join("capsule.sock"))
 }
 
-fn get_capsule_cwd() -> Result<PathBuf, Error> {
-    Ok(env::current_exe()
-        .map_err(|_| Error::InternalError)?
-        .parent()
-        .ok_or(Error::InternalError)?
-        .join(".capsule"))
+fn get_token_path() -> Result<PathBuf, Error> {
+    Ok(get_capsule_cwd()?.join("capsule.token"))
 }
 
-fn get_port_file_path() -> Result<PathBuf, Error> {
-    Ok(env::current_exe()
-        .map_err(|_| Error::InternalError)?
-        .parent()
-        .ok_or(Error::InternalError)?
-        .join(".capsule/capsule.port"))
+/// What the supervisor creates in the data directory, tear-down removes it
+fn created_paths(capsule: &Capsule) -> Vec<String> {
+    let mut paths: Vec<String> = ["logs", "capsule.sock", "capsule.token"]
+        .map(String::from)
+        .into();
+    paths.extend(extracted_paths(capsule));
+    paths.extend(
+        capsule
+            .processes
+            .iter()
+            .flatten()
+            .map(|(name, proc)| proc.cwd.clone().unwrap_or_else(|| name.clone())),
+    );
+    paths
 }
 
-fn get_port() -> Result<u16, Error> {
-    std::fs::read_to_string(get_port_file_path()?)
-        .map_err(|_| Error::SupervisorCantBeFound)?
-        .trim()
-        .parse()
-        .map_err(|_| Error::SupervisorCantBeFound)
+/// Files written by `extract_files`, relative to the data directory
+fn extracted_paths(capsule: &Capsule) -> Vec<String> {
+    let mut paths: Vec<String> = capsule
+        .embedded
+        .iter()
+        .flatten()
+        .map(|file| file.target.clone())
+        .collect();
+    for (name, proc) in capsule.processes.iter().flatten() {
+        let cwd = proc.cwd.as_deref().unwrap_or(name);
+        paths.extend(
+            proc.embedded
+                .iter()
+                .flatten()
+                .map(|file| format!("{}/{}", cwd, file.target)),
+        );
+    }
+    paths
 }
 
-fn daemon_run() -> Result<(), Error> {
-    let capsule: Capsule = from_bytes(&get_data()?)
-        .map_err(|_| Error::InvalidDataFormat)
-        .and_then(extract_files)?;
+fn daemon_run(env_overlay: bool, digest: Option<String>) -> Result<(), Error> {
+    // not inherited by processes and health checks, /proc/<pid>/environ keeps
+    // the environment the supervisor was started with though
+    // SAFETY: no other thread is running yet
+    unsafe { env::remove_var(PASSWORD_ENV) };
+    let capsule = {
+        let (mut payload, mut capsule, secrets_password) = get_data(digest.as_deref())?;
+        let overlay = match env_overlay {
+            true => read_env_overlay()?,
+            false => Env::new(),
+        };
+        // files the capsule did not create are neither replaced nor removed
+        data_dir::check_owned(extracted_paths(&capsule))?;
+        data_dir::claim(created_paths(&capsule))?;
+        extract::extract_files(&mut payload, &capsule, &get_capsule_cwd()?, None)?;
+        capsule.apply_instance(data_dir::instance()?);
+        process_env::resolve(&mut capsule, &overlay)?;
+        secrets::init(&capsule, payload.key(), secrets_password.as_deref())?;
+        capsule
+    };
 
     let mut table = HashMap::<String, RunningProcess>::new();
 
-    let socket = UdpSocket::bind("127.0.0.1:0").map_err(|_| Error::CouldNotStartUdpServer)?;
-    socket
-        .set_nonblocking(true)
-        .map_err(|_| Error::CouldNotStartUdpServer)?;
-
-    let port = socket
-        .local_addr()
-        .map_err(|_| Error::CouldNotStartUdpServer)?
-        .port();
-
-    let path = get_port_file_path()?;
-    let parent_dir = path.parent().ok_or(Error::InternalError)?;
+    let socket_path = get_socket_path()?;
+    let parent_dir = socket_path.parent().ok_or(Error::InternalError)?;
     fs::create_dir_all(parent_dir).set_error(Error::InternalError)?;
-    fs::write(path, port.to_string()).set_error(Error::InternalError)?;
-
-    let mut buf = [0u8; 4096];
-    fn start_child(
-        name: &String,
-        proc: &Process,
-        parent_env: Option<&Env>,
-    ) -> Result<Child, Error> {
-        let cwd = get_capsule_cwd()?.join(proc.cwd.as_ref().unwrap_or(name));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(parent_dir, fs::Permissions::from_mode(0o700))
+            .set_error(Error::InternalError)?;
+    }
+    let token = ipc::write_token(&get_token_path()?)?;
+    let listener = ipc::bind(&socket_path).set_error(Error::CouldNotStartControlSocket)?;
+
+    fn start_child(name: &String, proc: &Process) -> Result<Child, Error> {
+        let cwd = process_cwd(name, proc)?;
         let mut child = Command::new(&proc.cmd);
         child
             .args(proc.args.clone().unwrap_or_default())
             .current_dir(&cwd)
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit());
-        if let Some(env) = parent_env {
-            child.envs(env);
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        process_env::apply(&mut child, proc);
+        secrets::apply(&mut child, name);
+        let mut child = child
+            .spawn()
+            .set_error(Error::FailedToSpawnProcess(name.to_string()))?;
+        logs::capture(name, &mut child, proc.logs.as_ref())?;
+        Ok(child)
+    }
+
+    /// Spawns a new child for the process, counted restarts add to restarts and max_restarts
+    fn restart(proc: &mut RunningProcess, counted: bool) {
+        if counted {
+            proc.restarts += 1;
+            proc.restart_history.push_back(Instant::now());
         }
-        if let Some(env) = &proc.env {
-            child.envs(env);
+        match start_child(&proc.name, &proc.config) {
+            Ok(child) => {
+                proc.status = Status::Running(child.id());
+                proc.child = Some(child);
+                proc.started = Instant::now();
+                proc.health = HealthState::default();
+                proc.last_restart = Some(SystemTime::now());
+            }
+            Err(e) => {
+                e.log();
+                spawn_failed(proc);
+            }
         }
-        child
-            .spawn()
-            .set_error(Error::FailedToSpawnProcess(name.to_string()))
     }
 
-    if let Some(processes) = &capsule.processes {
-        for (name, proc) in processes {
-            let Ok(child) = start_child(&name, &proc, capsule.env.as_ref()) else {
-                continue;
-            };
-            let entry = RunningProcess {
-                name: name.clone(),
-                status: Status::Running(child.id()),
-                config: proc.clone(),
-                child,
-                started: Instant::now(),
-                force_restart: false,
-                restarts: 0,
-            };
-            table.insert(entry.name.clone(), entry);
+    /// The process could not be spawned, it is kept in the table as exited with
+    /// -1 and goes through its restart policy like a crash
+    fn spawn_failed(proc: &mut RunningProcess) {
+        proc.child = None;
+        proc.started = Instant::now();
+        exited(proc, -1);
+    }
+
+    /// Records the exit code and restarts the process when its policy asks for it
+    fn exited(proc: &mut RunningProcess, code: i32) {
+        proc.last_exit_code = Some(code);
+        proc.status = Status::Exited(code);
+        let restart = proc
+            .config
+            .restart_policy
+            .as_ref()
+            .unwrap_or(&RestartPolicy::Never);
+        if (code != 0 && restart == &RestartPolicy::OnFailure) || restart == &RestartPolicy::Always
+        {
+            schedule_restart(proc);
+        }
+    }
+
+    /// Waits for the backoff delay before the next restart, or gives up with
+    /// CrashLooping once max_restarts are reached within restart_window
+    fn schedule_restart(proc: &mut RunningProcess) {
+        let now = Instant::now();
+        if let Some(window) = proc.config.restart_window {
+            let window = Duration::from_millis(window);
+            while proc
+                .restart_history
+                .front()
+                .is_some_and(|t| now.duration_since(*t) > window)
+            {
+                proc.restart_history.pop_front();
+            }
+        }
+        if proc
+            .config
+            .max_restarts
+            .is_some_and(|max| proc.restart_history.len() >= max as usize)
+        {
+            proc.status = Status::CrashLooping;
+            return;
         }
+        // a process that stayed up longer than the longest delay starts over
+        let stable = proc
+            .config
+            .restart_backoff
+            .as_ref()
+            .and_then(|b| b.max)
+            .unwrap_or(DEFAULT_BACKOFF_MAX);
+        if proc.started.elapsed() >= Duration::from_millis(stable) {
+            proc.backoff_attempt = 0;
+        }
+        proc.next_restart = Some(now + proc.config.next_restart_delay(proc.backoff_attempt));
+        proc.backoff_attempt += 1;
+    }
+
+    /// A dependency that could not be spawned has no child and is not met
+    fn dependency_met(dep: &RunningProcess, condition: DependencyCondition) -> bool {
+        match condition {
+            DependencyCondition::Started => {
+                dep.child.is_some()
+                    && matches!(
+                        dep.status,
+                        Status::Running(_)
+                            | Status::Unhealthy(_)
+                            | Status::Exited(_)
+                            | Status::CrashLooping
+                    )
+            }
+            DependencyCondition::Healthy => {
+                matches!(dep.status, Status::Running(_))
+                    && (dep.config.health_check.is_none() || dep.health.healthy)
+            }
+            DependencyCondition::CompletedSuccessfully => dep.status == Status::Exited(0),
+        }
+    }
+
+    // processes are started in dependency order and stopped in reverse
+    let order = match &capsule.processes {
+        Some(processes) => start_order(processes)?,
+        None => vec![],
+    };
+    for name in &order {
+        let proc = &capsule.processes.as_ref().ok_or(Error::InternalError)?[name];
+        let entry = RunningProcess::new(name, proc.clone());
+        table.insert(entry.name.clone(), entry);
     }
 
     let mut s = System::new();
     let mut pids = table
-        .iter()
-        .map(|(_, p)| Pid::from_u32(p.child.id()))
+        .values()
+        .filter_map(|p| p.child.as_ref())
+        .map(|c| Pid::from_u32(c.id()))
         .collect::<Vec<_>>();
 
     let pid = get_current_pid().map(|p| vec![p]).unwrap_or_default();
     pids.append(&mut pid.clone());
     let mut last_refresh = Instant::now();
 
+    let shutdown = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    for signal in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        signal_hook::flag::register(signal, Arc::clone(&shutdown))
+            .set_error(Error::InternalError)?;
+    }
+
     loop {
-        if let Ok((len, client_addr)) = socket.recv_from(&mut buf) {
-            if let Ok(msg) = from_bytes::<CliMessage>(&buf[..len]) {
-                match msg {
-                    CliMessage::Kill { name } => {
-                        if let Some(entry) = table.get_mut(&name) {
-                            match entry.child.kill() {
-                                Ok(_) => {
-                                    entry.status = Status::Killed;
-                                    entry.child.try_wait().ok();
-                                    to_allocvec(&SupervisorResp::Ok)
-                                }
-                                Err(_) => to_allocvec(&SupervisorResp::Error(Error::InternalError)),
-                            }
+        if shutdown.load(Ordering::Relaxed) {
+            stop::stop_all(&mut table, order.iter().rev());
+            fs::remove_file(&socket_path).ok();
+            secrets::clear();
+            return Ok(());
+        }
+        if let Ok(mut stream) = ipc::accept(&listener)
+            && let Some(msg) = ipc::read_request(&mut stream, &token)
+        {
+            match msg {
+                CliMessage::Kill { name } => {
+                    let resp = if table.contains_key(&name) {
+                        let stopped = stop::stop_all(&mut table, std::iter::once(&name));
+                        SupervisorResp::Stopped(stopped)
+                    } else {
+                        SupervisorResp::Error(Error::ProcessNotFound(name))
+                    };
+                    ipc::respond(&mut stream, &resp).log();
+                }
+                CliMessage::Restart { name } => {
+                    let resp = if let Some(entry) = table.get_mut(&name) {
+                        if entry.child.is_some() {
+                            let outcome = stop::stop(entry);
+                            entry.status = Status::Starting;
+                            entry.force_restart = true;
+                            entry.next_restart = None;
+                            SupervisorResp::Stopped(vec![(name, outcome)])
                         } else {
-                            to_allocvec(&SupervisorResp::Error(Error::ProcessNotFound(name)))
+                            // not started yet or failed to spawn, back behind its dependencies
+                            entry.status = Status::Waiting;
+                            entry.next_restart = None;
+                            SupervisorResp::Ok
                         }
-                        .ok()
-                        .map(|resp| socket.send_to(&resp, client_addr).ok())
-                        .log(Error::InternalError);
-                    }
-                    CliMessage::Restart { name } => {
-                        if let Some(entry) = table.get_mut(&name) {
-                            match entry.child.kill() {
-                                Ok(_) => {
-                                    entry.status = Status::Starting;
-                                    entry.force_restart = true;
-                                    entry.child.try_wait().ok();
-                                    to_allocvec(&SupervisorResp::Ok)
-                                }
-                                Err(_) => to_allocvec(&SupervisorResp::Error(Error::InternalError)),
-                            }
-                        } else {
-                            to_allocvec(&SupervisorResp::Error(Error::ProcessNotFound(name)))
+                    } else {
+                        SupervisorResp::Error(Error::ProcessNotFound(name))
+                    };
+                    ipc::respond(&mut stream, &resp).log();
+                }
+                CliMessage::Start { name } => {
+                    let resp = match table.get_mut(&name) {
+                        Some(entry)
+                            if matches!(
+                                entry.status,
+                                Status::Killed
+                                    | Status::Stopped
+                                    | Status::Exited(_)
+                                    | Status::CrashLooping
+                            ) =>
+                        {
+                            // back behind its dependencies, with a clean restart history
+                            entry.status = Status::Waiting;
+                            entry.force_restart = false;
+                            entry.next_restart = None;
+                            entry.backoff_attempt = 0;
+                            entry.restart_history.clear();
+                            SupervisorResp::Ok
                         }
-                        .ok()
-                        .map(|resp| socket.send_to(&resp, client_addr).ok())
-                        .log(Error::InternalError);
-                    }
-                    CliMessage::List => {
-                        let table = table
-                            .iter()
-                            .map(|(_, p)| {
-                                let (cpu_usage, memory_usage, run_time, disk_usage) = s
-                                    .process(sysinfo::Pid::from_u32(p.child.id()))
-                                    .map(|i| {
-                                        let disk = i.disk_usage();
-                                        (
-                                            i.cpu_usage(),
-                                            i.memory(),
-                                            i.run_time(),
-                                            (disk.total_read_bytes, disk.total_written_bytes),
-                                        )
-                                    })
-                                    .unwrap_or_default();
-                                ListResp {
-                                    status: p.status,
-                                    name: p.name.clone(),
-                                    cpu_usage,
-                                    memory_usage,
-                                    disk_usage,
-                                    run_time,
-                                    restarts: p.restarts,
-                                }
-                            })
-                            .collect();
-                        let resp = SupervisorResp::List(table);
-                        to_allocvec(&resp)
-                            .map(|resp| socket.send_to(&resp, client_addr))
-                            .set_error(Error::InternalError)
-                            .log();
-                    }
-                    CliMessage::KillAll => {
-                        for (_, proc) in table.iter_mut() {
-                            match proc.child.kill() {
-                                Ok(_) => {
-                                    proc.status = Status::Killed;
-                                    proc.child.try_wait().ok();
-                                }
-                                Err(_) => {}
-                            };
+                        Some(_) => SupervisorResp::Error(Error::ProcessAlreadyRunning(name)),
+                        None => SupervisorResp::Error(Error::ProcessNotFound(name)),
+                    };
+                    ipc::respond(&mut stream, &resp).log();
+                }
+                CliMessage::Stop { name } => {
+                    let resp = match table.get_mut(&name) {
+                        Some(entry) => {
+                            let outcome = stop::stop(entry);
+                            entry.status = Status::Stopped;
+                            entry.force_restart = false;
+                            entry.next_restart = None;
+                            entry.next_run = None;
+                            SupervisorResp::Stopped(vec![(name, outcome)])
                         }
-                        to_allocvec(&SupervisorResp::Ok)
-                            .map(|resp| socket.send_to(&resp, client_addr))
-                            .set_error(Error::InternalError)
-                            .log();
-                    }
-                    CliMessage::TearDown => {
-                        for (_, proc) in table.iter_mut() {
-                            proc.child.kill().ok();
-                            proc.child.try_wait().ok();
+                        None => SupervisorResp::Error(Error::ProcessNotFound(name)),
+                    };
+                    ipc::respond(&mut stream, &resp).log();
+                }
+                CliMessage::ProcStatus { name } => {
+                    let resp = match table.get(&name) {
+                        Some(p) => {
+                            let pid = p.status.pid();
+                            let mut env_keys: Vec<String> = p
+                                .config
+                                .env
+                                .iter()
+                                .flat_map(|env| env.keys().cloned())
+                                .collect();
+                            env_keys.sort();
+                            let uses = p.config.use_secrets.as_deref().unwrap_or_default();
+                            let secret_keys = capsule
+                                .sealed_secrets
+                                .iter()
+                                .flat_map(|s| s.names(&p.name, uses).into_keys())
+                                .collect();
+                            let mut config = p.config.clone();
+                            config.env = None;
+                            SupervisorResp::ProcStatus(Box::new(ProcStatusResp {
+                                name,
+                                status: p.status,
+                                pid,
+                                config,
+                                env_keys,
+                                secret_keys,
+                                cwd: process_cwd(&p.name, &p.config)
+                                    .map(|cwd| cwd.display().to_string())
+                                    .unwrap_or_default(),
+                                restarts: p.restarts,
+                                last_exit_code: p.last_exit_code,
+                                last_restart: p.last_restart.and_then(unix_secs),
+                                uptime: pid.map(|_| p.started.elapsed().as_secs()),
+                                next_run: p.next_run.and_then(unix_secs),
+                            }))
                         }
-                        let resp = match clear_files() {
-                            Ok(_) => SupervisorResp::Ok,
-                            Err(_) => SupervisorResp::Error(Error::InternalError), // todo return proper error
-                        };
-                        to_allocvec(&resp)
-                            .map(|resp| socket.send_to(&resp, client_addr))
-                            .set_error(Error::InternalError)
-                            .log();
-                        return Ok(());
-                    }
-                    CliMessage::Status => {
-                        to_allocvec(&SupervisorResp::Version(capsule.version.clone()))
-                            .map(|resp| socket.send_to(&resp, client_addr))
-                            .set_error(Error::InternalError)
-                            .log();
-                    }
-                    CliMessage::KillDaemon => {
-                        to_allocvec(&SupervisorResp::Ok)
-                            .map(|resp| socket.send_to(&resp, client_addr))
-                            .set_error(Error::InternalError)
-                            .log();
-                        return Ok(());
-                    }
+                        None => SupervisorResp::Error(Error::ProcessNotFound(name)),
+                    };
+                    ipc::respond(&mut stream, &resp).log();
+                }
+                CliMessage::List => {
+                    let table = table
+                        .iter()
+                        .map(|(_, p)| {
+                            let (cpu_usage, memory_usage, run_time, disk_usage) = p
+                                .child
+                                .as_ref()
+                                .and_then(|c| s.process(sysinfo::Pid::from_u32(c.id())))
+                                .map(|i| {
+                                    let disk = i.disk_usage();
+                                    (
+                                        i.cpu_usage(),
+                                        i.memory(),
+                                        i.run_time(),
+                                        (disk.total_read_bytes, disk.total_written_bytes),
+                                    )
+                                })
+                                .unwrap_or_default();
+                            ListResp {
+                                status: p.status,
+                                name: p.name.clone(),
+                                cpu_usage,
+                                memory_usage,
+                                disk_usage,
+                                run_time,
+                                restarts: p.restarts,
+                                last_exit_code: p.last_exit_code,
+                                last_restart: p.last_restart.and_then(unix_secs),
+                                last_run: p.last_run.and_then(unix_secs),
+                                last_run_duration: p
+                                    .last_run_duration
+                                    .map(|d| d.as_millis() as u64),
+                                next_run: p.next_run.and_then(unix_secs),
+                            }
+                        })
+                        .collect();
+                    ipc::respond(&mut stream, &SupervisorResp::List(table)).log();
+                }
+                CliMessage::KillAll => {
+                    let stopped = stop::stop_all(&mut table, order.iter().rev());
+                    ipc::respond(&mut stream, &SupervisorResp::Stopped(stopped)).log();
+                }
+                CliMessage::TearDown => {
+                    let stopped = stop::stop_all(&mut table, order.iter().rev());
+                    secrets::clear();
+                    let resp = match data_dir::clear() {
+                        Ok(_) => SupervisorResp::Stopped(stopped),
+                        Err(e) => SupervisorResp::Error(e),
+                    };
+                    ipc::respond(&mut stream, &resp).log();
+                    return Ok(());
+                }
+                CliMessage::Status => {
+                    let resp = SupervisorResp::Version(capsule.version.clone());
+                    ipc::respond(&mut stream, &resp).log();
+                }
+                CliMessage::KillDaemon => {
+                    ipc::respond(&mut stream, &SupervisorResp::Ok).log();
+                    fs::remove_file(&socket_path).ok();
+                    secrets::clear();
+                    return Ok(());
+                }
+            }
+        }
+
+        for name in &order {
+            let Some(proc) = table.get(name) else {
+                continue;
+            };
+            if proc.status != Status::Waiting {
+                continue;
+            }
+            let ready = proc
+                .config
+                .depends_on
+                .iter()
+                .flatten()
+                .all(|(dep, condition)| {
+                    table
+                        .get(dep)
+                        .is_some_and(|dep| dependency_met(dep, *condition))
+                });
+            if !ready {
+                continue;
+            }
+            if let Some(schedule) = &proc.config.schedule {
+                let next_run = schedule.next_run(SystemTime::now());
+                let proc = table.get_mut(name).ok_or(Error::InternalError)?;
+                proc.status = Status::Scheduled;
+                proc.next_run = next_run;
+                continue;
+            }
+            match start_child(name, &proc.config) {
+                Ok(child) => {
+                    let proc = table.get_mut(name).ok_or(Error::InternalError)?;
+                    proc.status = Status::Running(child.id());
+                    proc.child = Some(child);
+                    proc.started = Instant::now();
+                    proc.health = HealthState::default();
+                }
+                Err(e) => {
+                    e.log();
+                    spawn_failed(table.get_mut(name).ok_or(Error::InternalError)?);
                 }
             }
         }
 
         for (_, proc) in table.iter_mut() {
-            if proc.status == Status::Killed {
+            if matches!(proc.status, Status::Killed | Status::Stopped)
+                || proc.config.schedule.is_some()
+            {
                 continue;
             }
-            let next_run = Duration::from_millis(proc.config.restart_delay.unwrap_or(10));
-            if proc.started.elapsed() < next_run {
+            if let Some(at) = proc.next_restart {
+                if Instant::now() >= at {
+                    proc.next_restart = None;
+                    restart(proc, true);
+                }
                 continue;
             }
-            match proc.child.try_wait() {
-                Ok(Some(status)) => {
-                    let (should_restart, inc) = if proc.force_restart {
-                        proc.force_restart = false;
-                        (true, 0)
-                    } else {
-                        let restart = proc
-                            .config
-                            .restart_policy
-                            .as_ref()
-                            .unwrap_or(&RestartPolicy::Never);
-                        (
-                            (!status.success() && restart == &RestartPolicy::OnFailure)
-                                || (restart == &RestartPolicy::Always),
-                            1,
-                        )
-                    };
-                    if should_restart {
-                        start_child(&proc.name, &proc.config, capsule.env.as_ref())
-                            .map(|child| {
-                                proc.status = Status::Running(child.id());
-                                proc.child = child;
-                                proc.restarts += inc;
-                                proc.started = Instant::now();
-                            })
-                            .ok();
-                    } else {
-                        proc.status = Status::Exited(status.code().unwrap_or(-9999))
-                    }
+            if health::check(proc) {
+                // restarted like a crash, with the backoff and max_restarts
+                if let Some(child) = &mut proc.child {
+                    let (signal, timeout) = stop::settings(&proc.config);
+                    stop::stop_child(child, signal, timeout);
+                    let status = child.try_wait().ok().flatten();
+                    let code = status.and_then(|s| s.code()).unwrap_or(-9999);
+                    proc.last_exit_code = Some(code);
+                    proc.status = Status::Exited(code);
                 }
-                _ => continue,
+                schedule_restart(proc);
+                continue;
+            }
+            if !matches!(
+                proc.status,
+                Status::Running(_) | Status::Unhealthy(_) | Status::Starting
+            ) {
+                continue;
+            }
+            let Some(Ok(Some(status))) = proc.child.as_mut().map(|c| c.try_wait()) else {
+                continue;
+            };
+            let code = status.code().unwrap_or(-9999);
+            if proc.force_restart {
+                proc.last_exit_code = Some(code);
+                proc.force_restart = false;
+                restart(proc, false);
+                continue;
+            }
+            exited(proc, code);
+        }
+
+        for (_, proc) in table.iter_mut() {
+            let Some(schedule) = &proc.config.schedule else {
+                continue;
             };
+            if matches!(
+                proc.status,
+                Status::Waiting | Status::Killed | Status::Stopped
+            ) {
+                continue;
+            }
+            if matches!(
+                proc.status,
+                Status::Running(_) | Status::Unhealthy(_) | Status::Starting
+            ) && let Some(Ok(Some(status))) = proc.child.as_mut().map(|c| c.try_wait())
+            {
+                let code = status.code().unwrap_or(-9999);
+                proc.last_exit_code = Some(code);
+                proc.last_run_duration = Some(proc.started.elapsed());
+                proc.status = Status::Exited(code);
+            }
+            proc.overlapping
+                .retain_mut(|(child, started)| match child.try_wait() {
+                    Ok(None) => true,
+                    Ok(Some(status)) => {
+                        proc.last_exit_code = Some(status.code().unwrap_or(-9999));
+                        proc.last_run_duration = Some(started.elapsed());
+                        false
+                    }
+                    Err(_) => false,
+                });
+            let running = matches!(proc.status, Status::Running(_));
+
+            let now = SystemTime::now();
+            if proc.force_restart && !running {
+                // `proc restart` runs the job right away
+                proc.force_restart = false;
+            } else if proc.next_run.is_some_and(|at| now >= at) {
+                proc.next_run = schedule.next_run(now);
+                if running && !proc.config.allow_overlap.unwrap_or(false) {
+                    logs::note(
+                        &proc.name,
+                        proc.config.logs.as_ref(),
+                        "skipping scheduled run, the previous one is still running",
+                    )
+                    .log();
+                    continue;
+                }
+            } else {
+                continue;
+            }
+            match start_child(&proc.name, &proc.config) {
+                Ok(child) => {
+                    if let Some(previous) = proc.child.take().filter(|_| running) {
+                        proc.overlapping.push((previous, proc.started));
+                    }
+                    proc.status = Status::Running(child.id());
+                    proc.child = Some(child);
+                    proc.started = Instant::now();
+                    proc.last_run = Some(now);
+                }
+                Err(e) => e.log(),
+            }
         }
 
         if last_refresh.elapsed() > sysinfo::MINIMUM_CPU_UPDATE_INTERVAL {
             pids = table
-                .iter()
-                .map(|(_, p)| sysinfo::Pid::from_u32(p.child.id()))
+                .values()
+                .filter_map(|p| p.child.as_ref())
+                .map(|c| sysinfo::Pid::from_u32(c.id()))
                 .collect::<Vec<_>>();
             pids.append(&mut pid.clone());
             s.refresh_processes_specifics(
@@ -399,146 +684,319 @@ fn daemon_run() -> Result<(), Error> {
     }
 }
 
-fn cli_daemon_start() -> Result<(), Error> {
-    if cli_daemon_status().is_ok() {
-        return Ok(());
+/// Reads a dotenv file, or a dotenv or json object from stdin for `-`
+fn read_env_file(path: &Path) -> Result<Env, Error> {
+    let content = match path.to_str() {
+        Some("-") => {
+            let mut content = String::new();
+            io::stdin()
+                .lock()
+                .read_to_string(&mut content)
+                .set_error(Error::CouldNotReadFile("stdin".to_string()))?;
+            content
+        }
+        _ => fs::read_to_string(path)
+            .set_error(Error::CouldNotReadFile(path.display().to_string()))?,
+    };
+    match content.trim_start().starts_with('{') {
+        true => serde_json::from_str(&content).map_err(|e| Error::InvalidConfig(e.to_string())),
+        false => parse_dotenv(&content),
+    }
+}
+
+fn cli_daemon_start(out: Output, key: &KeyArgs, env_file: Option<PathBuf>) -> Result<(), Error> {
+    if send_cli_cmd(CliMessage::Status, |_| Ok(())).is_ok() {
+        return out.print(&OkView::OK, || {});
     }
     let exe_path = env::current_exe().set_error(Error::InternalError)?;
-    let mut file =
-        File::open(&exe_path).set_error(Error::CouldNotReadFile(exe_path.display().to_string()))?;
-    file.seek(SeekFrom::End(-FOOTER_SIZE))
-        .set_error(Error::InternalError)?;
-    let mut footer_bytes = [0u8; FOOTER_SIZE as usize];
-    file.read_exact(&mut footer_bytes)
-        .set_error(Error::InternalError)?;
-    let magic = &footer_bytes[8..16];
-    // could be an attack vector
-    // if current_exe is swapped after the password read
-    // maybe include a checksum or something
+    let mut payload = open_payload()?;
+    let scheme = payload.scheme().clone();
+    let secrets_password = payload.settings()?.secrets_password;
     let mut cmd = Command::new(exe_path);
-    cmd.arg("supervisor");
-    if magic == MAGIC_NUMBER_ENCRYPTED {
-        cmd.env("__SUPERVISOR_PASSWORD__", read_password()?);
+    // the supervisor must not resolve another directory from its own environment,
+    // and refuses to run when the executable was swapped after this check
+    cmd.arg("supervisor")
+        .arg("--data-dir")
+        .arg(data_dir::base()?)
+        .arg("--instance")
+        .arg(data_dir::instance()?)
+        .arg("--digest")
+        .arg(hex(payload.digest()));
+    trust::forward(&mut cmd)?;
+    let mut passwords = vec![];
+    if scheme.is_encrypted() {
+        passwords.push(passwords::payload(&scheme)?);
+    }
+    if secrets_password {
+        passwords.push(passwords::secrets()?);
+    }
+    // read after the passwords, both can come from stdin
+    let overlay = env_file.as_deref().map(read_env_file).transpose()?;
+    if overlay.is_some() {
+        cmd.arg("--env-overlay");
+    }
+    if let Some(name) = key.env() {
+        cmd.env_remove(name);
     }
-    cmd.stdout(Stdio::null())
+    // passwords go through a pipe, the environment of a process can be read by
+    // its children and from /proc
+    let mut supervisor = cmd
+        .env_remove(PASSWORD_ENV)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
         .stderr(Stdio::null())
         .spawn()
         .set_error(Error::InternalError)?;
-    Ok(())
+    let mut stdin = supervisor.stdin.take().ok_or(Error::InternalError)?;
+    for password in passwords {
+        writeln!(stdin, "{}", password).set_error(Error::InternalError)?;
+    }
+    if let Some(overlay) = overlay {
+        let line = serde_json::to_string(&overlay).set_error(Error::InternalError)?;
+        writeln!(stdin, "{}", line).set_error(Error::InternalError)?;
+    }
+    drop(stdin);
+    out.print(&OkView::OK, || {})
 }
 
-fn get_socket() -> Result<(UdpSocket, u16), Error> {
-    let port = get_port()?;
-    let socket = UdpSocket::bind("127.0.0.1:0").set_error(Error::CouldNotStartUdpServer)?;
-    socket
-        .set_read_timeout(Some(Duration::from_secs(1)))
-        .set_error(Error::InternalError)?;
-    Ok((socket, port))
+/// Reads the manifest of the current executable, the supervisor does not need to run
+fn cli_info(out: Output) -> Result<(), Error> {
+    let (payload, capsule) = open_capsule(passwords::payload)?;
+    let info = CapsuleInfo::new(&capsule, payload.scheme());
+    out.print(&info, || print!("{}", info))
 }
 
+/// Reports whether the capsule is intact and who signed it, the exit code
+/// says whether it would be refused
+fn cli_verify(out: Output) -> Result<(), Error> {
+    let exe_path = env::current_exe().map_err(|_| Error::NoData)?;
+    let mut payload = Payload::open(&exe_path)?;
+    let keys = trust::keys()?;
+    let verification = trust::verification(&mut payload)?;
+    out.print(&verification, || print!("{}", verification))?;
+    if let Err(e) = verification.result(keys) {
+        // the report already says why
+        process::exit(e.exit_code());
+    }
+    Ok(())
+}
+
+const CLI_TIMEOUT: Duration = Duration::from_secs(1);
+/// Stopping waits for every process stop timeout before answering
+const CLI_STOP_TIMEOUT: Duration = Duration::from_secs(300);
+
 fn send_cli_cmd(
     req: CliMessage,
     cb: impl Fn(SupervisorResp) -> Result<(), Error>,
 ) -> Result<(), Error> {
-    let (socket, port) = get_socket()?;
-    let data = to_allocvec(&req).set_error(Error::InternalError)?;
-    socket
-        .send_to(&data, ("127.0.0.1", port))
-        .set_error(Error::SupervisorCantBeFound)?;
-    let mut buf = [0u8; 4096];
-    let len = socket
-        .recv(&mut buf)
-        .set_error(Error::SupervisorCantBeFound)?;
-    let resp: SupervisorResp = from_bytes(&buf[..len]).set_error(Error::InternalError)?;
+    send_cli_cmd_with_timeout(req, CLI_TIMEOUT, cb)
+}
+
+fn send_cli_cmd_with_timeout(
+    req: CliMessage,
+    timeout: Duration,
+    cb: impl Fn(SupervisorResp) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let resp = ipc::send(&get_socket_path()?, &get_token_path()?, req, timeout)?;
     if let SupervisorResp::Error(e) = &resp {
         return Err(e.clone());
     }
     cb(resp)
 }
 
-fn cli_proc_list() -> Result<(), Error> {
-    send_cli_cmd(CliMessage::List, |resp| {
-        match resp {
-            SupervisorResp::List(processes) => {
-                println!("{}", Table::from(processes));
-            }
-            _ => {}
+fn cli_proc_list(out: Output) -> Result<(), Error> {
+    send_cli_cmd(CliMessage::List, |resp| match resp {
+        SupervisorResp::List(processes) => {
+            let view: Vec<ProcessView> = processes.iter().map(ProcessView::from).collect();
+            out.print(&view, || println!("{}", Table::from(processes)))
+        }
+        _ => Err(Error::InternalError),
+    })
+}
+
+fn print_stopped(out: Output, resp: SupervisorResp) -> Result<(), Error> {
+    let SupervisorResp::Stopped(stopped) = resp else {
+        return Err(Error::InternalError);
+    };
+    let view: Vec<StoppedView> = stopped
+        .iter()
+        .map(|(name, outcome)| StoppedView {
+            name: name.clone(),
+            outcome: *outcome,
+        })
+        .collect();
+    out.print(&view, || {
+        for (name, outcome) in stopped {
+            println!("Process {} {}", name, outcome);
         }
-        return Ok(());
     })
 }
 
-fn cli_proc_kill(name: String) -> Result<(), Error> {
-    send_cli_cmd(CliMessage::Kill { name: name.clone() }, |_| Ok(()))
-        .set_error(Error::CouldNotKillProcess(name.clone()))?;
-    println!("Process {} killed!", name);
-    return Ok(());
+fn cli_proc_kill(out: Output, name: String) -> Result<(), Error> {
+    send_cli_cmd_with_timeout(CliMessage::Kill { name }, CLI_STOP_TIMEOUT, |resp| {
+        print_stopped(out, resp)
+    })
+}
+
+fn cli_proc_restart(out: Output, name: String) -> Result<(), Error> {
+    send_cli_cmd_with_timeout(
+        CliMessage::Restart { name: name.clone() },
+        CLI_STOP_TIMEOUT,
+        |resp| match resp {
+            SupervisorResp::Ok => out.print(&OkView::OK, || {}),
+            resp => print_stopped(out, resp),
+        },
+    )?;
+    if out == Output::Text {
+        println!("Process {} restarting!", name);
+    }
+    Ok(())
+}
+
+fn cli_proc_start(out: Output, name: String) -> Result<(), Error> {
+    send_cli_cmd(CliMessage::Start { name: name.clone() }, |_| {
+        out.print(&OkView::OK, || println!("Process {} starting!", name))
+    })
 }
 
-fn cli_proc_restart(name: String) -> Result<(), Error> {
-    send_cli_cmd(CliMessage::Restart { name: name.clone() }, |_| Ok(()))
-        .set_error(Error::CouldNotKillProcess(name.clone()))?;
-    println!("Process {} restarting!", name);
-    return Ok(());
+fn cli_proc_stop(out: Output, name: String) -> Result<(), Error> {
+    send_cli_cmd_with_timeout(CliMessage::Stop { name }, CLI_STOP_TIMEOUT, |resp| {
+        print_stopped(out, resp)
+    })
+}
+
+fn cli_proc_status(out: Output, name: String) -> Result<(), Error> {
+    send_cli_cmd(CliMessage::ProcStatus { name }, |resp| match resp {
+        SupervisorResp::ProcStatus(status) => out
+            .print(&ProcStatusView::from(status.as_ref()), || {
+                print!("{}", status)
+            }),
+        _ => Err(Error::InternalError),
+    })
 }
 
-fn cli_proc_kill_all() -> Result<(), Error> {
-    send_cli_cmd(CliMessage::KillAll, |_| Ok(()))?;
-    println!("Ok!");
-    return Ok(());
+fn cli_proc_kill_all(out: Output) -> Result<(), Error> {
+    send_cli_cmd_with_timeout(CliMessage::KillAll, CLI_STOP_TIMEOUT, |resp| {
+        print_stopped(out, resp)
+    })?;
+    if out == Output::Text {
+        println!("Ok!");
+    }
+    Ok(())
 }
 
-fn cli_daemon_tear_down() -> Result<(), Error> {
-    send_cli_cmd(CliMessage::TearDown, |_| Ok(()))?;
-    println!("Ok!");
-    return Ok(());
+fn cli_daemon_tear_down(out: Output) -> Result<(), Error> {
+    send_cli_cmd_with_timeout(CliMessage::TearDown, CLI_STOP_TIMEOUT, |resp| {
+        print_stopped(out, resp)
+    })?;
+    if out == Output::Text {
+        println!("Ok!");
+    }
+    Ok(())
 }
 
-fn cli_daemon_kill() -> Result<(), Error> {
+fn cli_daemon_kill(out: Output) -> Result<(), Error> {
     send_cli_cmd(CliMessage::KillDaemon, |_| Ok(()))?;
-    println!("Ok!");
-    return Ok(());
+    out.print(&OkView::OK, || println!("Ok!"))
 }
 
-fn cli_daemon_status() -> Result<(), Error> {
+fn cli_daemon_status(out: Output) -> Result<(), Error> {
     send_cli_cmd(CliMessage::Status, |resp| match resp {
         SupervisorResp::Version(v) => {
-            println!("Status.        : Ok");
-            println!("Capsule version: {}", v);
-            println!("Daemon  version: {}", env!("CARGO_PKG_VERSION"));
-            Ok(())
+            let view = VersionView {
+                capsule_version: Some(v.to_string()),
+                runtime_version: env!("CARGO_PKG_VERSION").to_string(),
+            };
+            out.print(&view, || {
+                println!("Status.        : Ok");
+                println!("Capsule version: {}", v);
+                println!("Daemon  version: {}", env!("CARGO_PKG_VERSION"));
+            })
         }
         _ => Err(Error::InternalError),
     })
 }
 
-fn cli_daemon_version() -> Result<(), Error> {
-    match send_cli_cmd(CliMessage::Status, |resp| match resp {
-        SupervisorResp::Version(v) => {
-            println!("Capsule version: {}", v);
-            println!("Daemon version: {}", env!("CARGO_PKG_VERSION"));
-            Ok(())
+fn cli_daemon_list_instances(out: Output) -> Result<(), Error> {
+    let instances: Vec<_> = data_dir::instances()?
+        .into_iter()
+        .map(|(name, dir)| {
+            let resp = ipc::send(
+                &dir.join("capsule.sock"),
+                &dir.join("capsule.token"),
+                CliMessage::Status,
+                CLI_TIMEOUT,
+            );
+            InstanceView {
+                name,
+                running: resp.is_ok(),
+                capsule_version: match resp {
+                    Ok(SupervisorResp::Version(v)) => Some(v.to_string()),
+                    _ => None,
+                },
+                data_dir: dir.display().to_string(),
+            }
+        })
+        .collect();
+    out.print(&instances, || {
+        let width = instances.iter().map(|i| i.name.len()).max().unwrap_or(0);
+        for instance in &instances {
+            let status = match &instance.capsule_version {
+                Some(v) => format!("running {}", v),
+                None => "stopped".to_string(),
+            };
+            println!(
+                "{:width$}  {:15}  {}",
+                instance.name,
+                status,
+                instance.data_dir,
+                width = width
+            );
         }
-        _ => Err(Error::InternalError),
-    }) {
-        Ok(v) => Ok(v),
-        Err(_) => {
-            println!("Daemon version: {}", env!("CARGO_PKG_VERSION"));
-            Ok(())
+    })
+}
+
+fn cli_daemon_version(out: Output) -> Result<(), Error> {
+    let capsule_version = ipc::send(
+        &get_socket_path()?,
+        &get_token_path()?,
+        CliMessage::Status,
+        CLI_TIMEOUT,
+    )
+    .ok()
+    .and_then(|resp| match resp {
+        SupervisorResp::Version(v) => Some(v),
+        _ => None,
+    });
+    let view = VersionView {
+        capsule_version: capsule_version.as_ref().map(|v| v.to_string()),
+        runtime_version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+    out.print(&view, || {
+        if let Some(v) = &capsule_version {
+            println!("Capsule version: {}", v);
         }
-    }
+        println!("Daemon version: {}", env!("CARGO_PKG_VERSION"));
+    })
 }
 
 #[derive(Debug, Subcommand)]
 enum Daemon {
     /// Starts the supervisor
-    Start,
+    Start {
+        /// Env vars overriding the capsule ones, from a dotenv file or `-` for
+        /// stdin, which also takes a json object
+        #[arg(long, value_name = "FILE")]
+        env_file: Option<PathBuf>,
+    },
     /// Warning! this will remove all files, and stop all processes and the supervisor
     TearDown,
     /// Returns the status
     Status,
     /// Kills the supervisor, use proc kill to kill a specific process
     Kill,
+    /// Lists the instances started from this data directory
+    ListInstances,
 }
 
 #[derive(Debug, Subcommand)]
@@ -547,15 +1005,33 @@ enum Proc {
     Kill { name: String },
     /// Restart a process
     Restart { name: String },
+    /// Starts a stopped, killed or exited process
+    Start { name: String },
+    /// Gracefully stops a process, it is not restarted until proc start
+    Stop { name: String },
+    /// Prints detailed information about a process
+    Status { name: String },
     /// Kills all a processes keeps the supervisor running
     KillAll,
     /// Lists data about all the processes
     List,
+    /// Prints the logs of a process
+    Logs {
+        name: String,
+        /// Keep printing new lines as they are written
+        #[arg(short, long)]
+        follow: bool,
+        /// Number of lines to print
+        #[arg(short = 'n', long, default_value_t = 100)]
+        lines: usize,
+        /// Read stderr instead of stdout
+        #[arg(long)]
+        stderr: bool,
+    },
 }
 
-#[derive(Parser, Debug)]
-#[command(disable_version_flag = true, about, long_about = None, before_help=ASCII_ART)]
-enum Args {
+#[derive(Debug, Subcommand)]
+enum Cmd {
     #[command(subcommand)]
     Daemon(Daemon),
 
@@ -563,30 +1039,105 @@ enum Args {
     Proc(Proc),
 
     #[clap(hide = true)]
-    Supervisor,
+    Supervisor {
+        /// A json env overlay follows the passwords on stdin
+        #[arg(long)]
+        env_overlay: bool,
+
+        /// Digest of the executable `daemon start` checked
+        #[arg(long)]
+        digest: Option<String>,
+    },
 
     #[clap(about = "Print version")]
     Version,
+
+    #[clap(about = "Print the processes and files bundled in this capsule")]
+    Info,
+
+    #[clap(about = "Check the digest and the signature of this capsule")]
+    Verify,
+
+    #[clap(about = "Write the bundled files to a directory without starting anything")]
+    Extract {
+        /// Directory to extract to, defaults to the current directory
+        #[arg(long)]
+        to: Option<PathBuf>,
+
+        /// Only extract the files of this process
+        #[arg(long)]
+        only: Option<String>,
+    },
+}
+
+#[derive(Parser, Debug)]
+#[command(disable_version_flag = true, about, long_about = None, before_help=ASCII_ART)]
+struct Args {
+    /// Output format, errors are printed to stdout in json and yaml
+    #[arg(short, long, global = true, value_enum, default_value_t = Output::Text)]
+    output: Output,
+
+    /// Directory for extracted files, logs and the control socket,
+    /// takes precedence over CAPSULE_DATA_DIR and the capsule data_dir
+    #[arg(long, global = true, value_name = "DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// Runs a separate copy of the capsule with its own files, socket, logs
+    /// and the env overlays of `instances.<name>`
+    #[arg(long, global = true, value_name = "NAME")]
+    instance: Option<String>,
+
+    #[command(flatten)]
+    key: KeyArgs,
+
+    #[command(flatten)]
+    trust: TrustArgs,
+
+    #[command(subcommand)]
+    command: Cmd,
 }
 
 fn main() {
     let args = Args::parse();
+    let out = args.output;
+    if let Err(e) = data_dir::init(args.data_dir, args.instance)
+        .and(passwords::init(&args.key))
+        .and(trust::init(&args.trust))
+    {
+        out.exit(Err(e));
+    }
 
-    match args {
-        Args::Daemon(daemon) => match daemon {
-            Daemon::Start => cli_daemon_start(),
-            Daemon::TearDown => cli_daemon_tear_down(),
-            Daemon::Kill => cli_daemon_kill(),
-            Daemon::Status => cli_daemon_status(),
+    let result = match args.command {
+        Cmd::Daemon(daemon) => match daemon {
+            Daemon::Start { env_file } => cli_daemon_start(out, &args.key, env_file),
+            Daemon::TearDown => cli_daemon_tear_down(out),
+            Daemon::Kill => cli_daemon_kill(out),
+            Daemon::Status => cli_daemon_status(out),
+            Daemon::ListInstances => cli_daemon_list_instances(out),
         },
-        Args::Proc(proc) => match proc {
-            Proc::Kill { name } => cli_proc_kill(name),
-            Proc::Restart { name } => cli_proc_restart(name),
-            Proc::KillAll => cli_proc_kill_all(),
-            Proc::List => cli_proc_list(),
+        Cmd::Proc(proc) => match proc {
+            Proc::Kill { name } => cli_proc_kill(out, name),
+            Proc::Restart { name } => cli_proc_restart(out, name),
+            Proc::Start { name } => cli_proc_start(out, name),
+            Proc::Stop { name } => cli_proc_stop(out, name),
+            Proc::Status { name } => cli_proc_status(out, name),
+            Proc::KillAll => cli_proc_kill_all(out),
+            Proc::List => cli_proc_list(out),
+            Proc::Logs {
+                name,
+                follow,
+                lines,
+                stderr,
+            } => logs::cli_proc_logs(out, name, follow, lines, stderr),
         },
-        Args::Supervisor => daemon_run(),
-        Args::Version => cli_daemon_version(),
-    }
-    .exit();
+        Cmd::Supervisor {
+            env_overlay,
+            digest,
+        } => daemon_run(env_overlay, digest),
+        Cmd::Version => cli_daemon_version(out),
+        Cmd::Info => cli_info(out),
+        Cmd::Verify => cli_verify(out),
+        Cmd::Extract { to, only } => extract::cli_extract(out, to, only),
+    };
+    out.exit(result);
 }
@@ -0,0 +1,373 @@
+//! Machine readable output of the CLI.
+//!
+//! The views below are the contract with scripts: field names do not change,
+//! units are part of the name and timestamps are unix seconds.
+
+use capsules_lib::{Error, ListResp, ProcStatusResp, Process, StopOutcome};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::process;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum Output {
+    #[default]
+    Text,
+    Json,
+    Yaml,
+}
+
+impl Output {
+    /// Prints `value` in the selected format, `text` is called in text mode
+    pub fn print<T: Serialize>(self, value: &T, text: impl FnOnce()) -> Result<(), Error> {
+        match self {
+            Output::Text => text(),
+            Output::Json => println!(
+                "{}",
+                serde_json::to_string_pretty(value).map_err(|_| Error::InternalError)?
+            ),
+            Output::Yaml => print!(
+                "{}",
+                serde_yaml::to_string(value).map_err(|_| Error::InternalError)?
+            ),
+        }
+        Ok(())
+    }
+
+    /// Exits with the code matching the result, errors go to stdout unless in text mode
+    pub fn exit(self, result: Result<(), Error>) -> ! {
+        let Err(e) = result else {
+            process::exit(0);
+        };
+        if self == Output::Text {
+            e.exit();
+        }
+        let view = ErrorView {
+            error: ErrorDetails {
+                message: e.to_string(),
+                exit_code: e.exit_code(),
+            },
+        };
+        if self.print(&view, || {}).is_err() {
+            e.exit();
+        }
+        process::exit(e.exit_code());
+    }
+}
+
+#[derive(Serialize)]
+pub struct ErrorView {
+    pub error: ErrorDetails,
+}
+
+#[derive(Serialize)]
+pub struct ErrorDetails {
+    pub message: String,
+    pub exit_code: i32,
+}
+
+#[derive(Serialize)]
+pub struct OkView {
+    pub ok: bool,
+}
+
+impl OkView {
+    pub const OK: OkView = OkView { ok: true };
+}
+
+#[derive(Serialize)]
+pub struct VersionView {
+    /// None when the supervisor is not running
+    pub capsule_version: Option<String>,
+    pub runtime_version: String,
+}
+
+#[derive(Serialize)]
+pub struct StoppedView {
+    pub name: String,
+    pub outcome: StopOutcome,
+}
+
+#[derive(Serialize)]
+pub struct ProcessView {
+    pub name: String,
+    pub status: &'static str,
+    pub pid: Option<u32>,
+    pub cpu_usage_percent: f32,
+    pub memory_bytes: u64,
+    pub disk_read_bytes: u64,
+    pub disk_written_bytes: u64,
+    pub run_time_secs: u64,
+    pub restarts: u32,
+    pub last_exit_code: Option<i32>,
+    pub last_restart_unix_secs: Option<u64>,
+    pub last_run_unix_secs: Option<u64>,
+    pub last_run_duration_ms: Option<u64>,
+    pub next_run_unix_secs: Option<u64>,
+}
+
+impl From<&ListResp> for ProcessView {
+    fn from(p: &ListResp) -> Self {
+        ProcessView {
+            name: p.name.clone(),
+            status: p.status.name(),
+            pid: p.status.pid(),
+            cpu_usage_percent: p.cpu_usage,
+            memory_bytes: p.memory_usage,
+            disk_read_bytes: p.disk_usage.0,
+            disk_written_bytes: p.disk_usage.1,
+            run_time_secs: p.run_time,
+            restarts: p.restarts,
+            last_exit_code: p.last_exit_code,
+            last_restart_unix_secs: p.last_restart,
+            last_run_unix_secs: p.last_run,
+            last_run_duration_ms: p.last_run_duration,
+            next_run_unix_secs: p.next_run,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ProcStatusView<'a> {
+    pub name: &'a str,
+    pub status: &'static str,
+    pub pid: Option<u32>,
+    pub cwd: &'a str,
+    /// Sorted
+    pub env_keys: &'a [String],
+    pub uptime_secs: Option<u64>,
+    pub restarts: u32,
+    pub last_exit_code: Option<i32>,
+    pub last_restart_unix_secs: Option<u64>,
+    pub next_run_unix_secs: Option<u64>,
+    /// Same fields as in the capsule config, env values are left out
+    pub config: &'a Process,
+}
+
+impl<'a> From<&'a ProcStatusResp> for ProcStatusView<'a> {
+    fn from(p: &'a ProcStatusResp) -> Self {
+        ProcStatusView {
+            name: &p.name,
+            status: p.status.name(),
+            pid: p.pid,
+            cwd: &p.cwd,
+            env_keys: &p.env_keys,
+            uptime_secs: p.uptime,
+            restarts: p.restarts,
+            last_exit_code: p.last_exit_code,
+            last_restart_unix_secs: p.last_restart,
+            next_run_unix_secs: p.next_run,
+            config: &p.config,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct LogsView<'a> {
+    pub name: &'a str,
+    /// stdout or stderr
+    pub stream: &'a str,
+    pub lines: &'a [String],
+}
+
+#[derive(Serialize)]
+pub struct ExtractedView {
+    pub to: String,
+    pub files: usize,
+}
+
+#[derive(Serialize)]
+pub struct InstanceView {
+    pub name: String,
+    pub running: bool,
+    /// None when the supervisor is not running
+    pub capsule_version: Option<String>,
+    pub data_dir: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capsules_lib::Status;
+    use serde_json::{Value, json};
+
+    /// Checks the json output and that the yaml one holds the same fields
+    fn check<T: Serialize>(view: &T, expected: Value) {
+        assert_eq!(serde_json::to_value(view).unwrap(), expected);
+        let yaml = serde_yaml::to_string(view).unwrap();
+        assert_eq!(serde_yaml::from_str::<Value>(&yaml).unwrap(), expected);
+    }
+
+    #[test]
+    fn small_views_are_serialized() {
+        check(&OkView::OK, json!({"ok": true}));
+        check(
+            &ErrorView {
+                error: ErrorDetails {
+                    message: "Supervisor can't be found".to_string(),
+                    exit_code: 3,
+                },
+            },
+            json!({"error": {"message": "Supervisor can't be found", "exit_code": 3}}),
+        );
+        check(
+            &VersionView {
+                capsule_version: None,
+                runtime_version: "0.1.0".to_string(),
+            },
+            json!({"capsule_version": null, "runtime_version": "0.1.0"}),
+        );
+        check(
+            &StoppedView {
+                name: "api".to_string(),
+                outcome: StopOutcome::Killed,
+            },
+            json!({"name": "api", "outcome": "killed"}),
+        );
+        check(
+            &LogsView {
+                name: "api",
+                stream: "stderr",
+                lines: &["a".to_string(), "b".to_string()],
+            },
+            json!({"name": "api", "stream": "stderr", "lines": ["a", "b"]}),
+        );
+        check(
+            &ExtractedView {
+                to: "out".to_string(),
+                files: 3,
+            },
+            json!({"to": "out", "files": 3}),
+        );
+        check(
+            &InstanceView {
+                name: "default".to_string(),
+                running: true,
+                capsule_version: Some("1.0.0".to_string()),
+                data_dir: "/srv/app".to_string(),
+            },
+            json!({
+                "name": "default",
+                "running": true,
+                "capsule_version": "1.0.0",
+                "data_dir": "/srv/app"
+            }),
+        );
+    }
+
+    #[test]
+    fn process_view_is_serialized() {
+        let resp = ListResp {
+            status: Status::Running(42),
+            name: "api".to_string(),
+            cpu_usage: 1.5,
+            memory_usage: 2048,
+            disk_usage: (10, 20),
+            restarts: 1,
+            run_time: 60,
+            last_exit_code: Some(137),
+            last_restart: Some(1_700_000_000),
+            last_run: None,
+            last_run_duration: None,
+            next_run: None,
+        };
+        check(
+            &ProcessView::from(&resp),
+            json!({
+                "name": "api",
+                "status": "running",
+                "pid": 42,
+                "cpu_usage_percent": 1.5,
+                "memory_bytes": 2048,
+                "disk_read_bytes": 10,
+                "disk_written_bytes": 20,
+                "run_time_secs": 60,
+                "restarts": 1,
+                "last_exit_code": 137,
+                "last_restart_unix_secs": 1_700_000_000,
+                "last_run_unix_secs": null,
+                "last_run_duration_ms": null,
+                "next_run_unix_secs": null
+            }),
+        );
+    }
+
+    #[test]
+    fn proc_status_view_is_serialized() {
+        let config: Process = serde_json::from_str(r#"{"cmd": "./api"}"#).unwrap();
+        let resp = ProcStatusResp {
+            name: "api".to_string(),
+            status: Status::Exited(1),
+            pid: None,
+            config: config.clone(),
+            env_keys: vec!["PORT".to_string()],
+            secret_keys: vec!["TOKEN".to_string()],
+            cwd: "/srv/app/api".to_string(),
+            restarts: 2,
+            last_exit_code: Some(1),
+            last_restart: None,
+            uptime: None,
+            next_run: None,
+        };
+        check(
+            &ProcStatusView::from(&resp),
+            json!({
+                "name": "api",
+                "status": "exited",
+                "pid": null,
+                "cwd": "/srv/app/api",
+                "env_keys": ["PORT"],
+                "uptime_secs": null,
+                "restarts": 2,
+                "last_exit_code": 1,
+                "last_restart_unix_secs": null,
+                "next_run_unix_secs": null,
+                "config": serde_json::to_value(&config).unwrap()
+            }),
+        );
+    }
+
+    /// Documented in the Readme, scripts depend on them
+    #[test]
+    fn exit_codes_are_stable() {
+        let s = String::new;
+        let cases = [
+            (Error::ProcessNotFound(s()), 4),
+            (Error::SupervisorCantBeFound, 3),
+            (Error::CouldNotStartControlSocket, 1),
+            (Error::Unauthorized, 6),
+            (Error::NoData, 7),
+            (Error::InvalidPassword, 6),
+            (Error::InvalidDataFormat, 7),
+            (Error::InvalidConfig(s()), 7),
+            (Error::CouldNotFindFile(s()), 1),
+            (Error::CouldNotReadFile(s()), 1),
+            (Error::CouldNotCreatePath(s()), 1),
+            (Error::InternalError, 1),
+            (Error::CouldNotWriteFile(s()), 1),
+            (Error::CouldNotKillProcess(s()), 1),
+            (Error::FailedToSpawnProcess(s()), 1),
+            (Error::CouldNotEncryptFile, 1),
+            (Error::UnsupportedTarget(s()), 1),
+            (Error::UnknownDependency(s(), s()), 7),
+            (Error::DependencyCycle(vec![]), 7),
+            (Error::InvalidSchedule(s(), s()), 7),
+            (Error::ProcessAlreadyRunning(s()), 5),
+            (Error::UnsupportedOutput(s()), 2),
+            (Error::InvalidPattern(s()), 7),
+            (Error::NoFilesMatched(s()), 7),
+            (Error::DuplicateTarget(s()), 7),
+            (Error::UnsafePath(s()), 7),
+            (Error::InvalidInstanceName(s()), 2),
+            (Error::InvalidProcessName(s()), 7),
+            (Error::InvalidEnv(s(), s()), 7),
+            (Error::UnsupportedFormat(2), 7),
+            (Error::IntegrityCheckFailed, 7),
+            (Error::SignatureCheckFailed(s()), 8),
+            (Error::NoTmpfs, 1),
+            (Error::NotOwned(s()), 1),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.exit_code(), code, "{error:?}");
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Passwords of encrypted payloads and sealed secrets, or the hex of the key for
+//! payloads sealed with a key or for a recipient. Asked for on a terminal,
+//! read line by line from stdin otherwise, or taken from `--key-file`,
+//! `--key-fd` or `--key-env`. Every source holds one password per line in the
+//! order they are asked for: the payload one, then the secrets one when the
+//! secrets have a password of their own.
+
+use capsules_lib::payload::Scheme;
+use capsules_lib::{Error, SetError};
+use clap::Args;
+use rpassword::{prompt_password, read_password_from_bufread};
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+#[derive(Args, Debug)]
+#[group(multiple = false)]
+pub struct KeyArgs {
+    /// Read the passwords from a file instead of asking for them
+    #[arg(long, global = true, value_name = "FILE")]
+    key_file: Option<PathBuf>,
+
+    /// Read the passwords from an inherited file descriptor
+    #[arg(long, global = true, value_name = "FD")]
+    key_fd: Option<u32>,
+
+    /// Read the passwords from an env var, removed from the supervisor environment
+    #[arg(long, global = true, value_name = "NAME")]
+    key_env: Option<String>,
+}
+
+impl KeyArgs {
+    /// Env var `daemon start` must not hand to the supervisor
+    pub fn env(&self) -> Option<&str> {
+        self.key_env.as_deref()
+    }
+}
+
+/// Passwords left from the key source, None when they are asked for
+static PASSWORDS: Mutex<Option<VecDeque<String>>> = Mutex::new(None);
+
+pub fn init(key: &KeyArgs) -> Result<(), Error> {
+    let content = if let Some(path) = &key.key_file {
+        fs::read_to_string(path).set_error(Error::CouldNotReadFile(path.display().to_string()))?
+    } else if let Some(fd) = key.key_fd {
+        let path = format!("/dev/fd/{}", fd);
+        fs::read_to_string(&path).set_error(Error::CouldNotReadFile(path))?
+    } else if let Some(name) = &key.key_env {
+        env::var(name)
+            .map_err(|_| Error::InvalidConfig(format!("env var {:?} is not set", name)))?
+    } else {
+        return Ok(());
+    };
+    let passwords = content
+        .lines()
+        .map(|line| line.trim().to_string())
+        .collect();
+    *PASSWORDS.lock().map_err(|_| Error::InternalError)? = Some(passwords);
+    Ok(())
+}
+
+/// Password or key of the payload, depending on `scheme`
+pub fn payload(scheme: &Scheme) -> Result<String, Error> {
+    next(scheme.prompt())
+}
+
+pub fn secrets() -> Result<String, Error> {
+    next("Enter secrets password: ")
+}
+
+fn next(prompt: &str) -> Result<String, Error> {
+    if let Some(passwords) = PASSWORDS.lock().map_err(|_| Error::InternalError)?.as_mut() {
+        return passwords.pop_front().ok_or(Error::InvalidPassword);
+    }
+    // the stdin buffer is shared so consecutive calls read consecutive lines
+    if !atty::is(atty::Stream::Stdin) {
+        return read_password_from_bufread(&mut io::stdin().lock())
+            .map(|e| e.trim().to_string())
+            .set_error(Error::InternalError);
+    }
+    prompt_password(prompt).set_error(Error::InternalError)
+}
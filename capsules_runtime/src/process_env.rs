@@ -0,0 +1,60 @@
+//! Env of the spawned processes, resolved once when the supervisor starts so
+//! a bad reference fails `daemon start` instead of every spawn.
+
+use crate::{PASSWORD_ENV, data_dir, process_cwd};
+use capsules_lib::env::{
+    CAPSULE_DIR, CAPSULE_INSTANCE, PROCESS_CWD, passthrough_allows, resolve_env,
+};
+use capsules_lib::{Capsule, Env, Error, Process};
+use std::env;
+use std::process::Command;
+
+/// Replaces the env of every process with the resolved built-ins, global and
+/// process vars and `overlay`, and its passthrough with the global one extended
+/// by its own. The global env is left empty.
+pub fn resolve(capsule: &mut Capsule, overlay: &Env) -> Result<(), Error> {
+    let global = capsule.env.take().unwrap_or_default();
+    let passthrough = capsule.env_passthrough.take();
+    let capsule_dir = data_dir::get()?.display().to_string();
+    let instance = data_dir::instance()?.to_string();
+    let host = |name: &str| match name {
+        PASSWORD_ENV => None,
+        _ => env::var(name).ok(),
+    };
+
+    for (name, proc) in capsule.processes.iter_mut().flatten() {
+        let mut merged = Env::from([
+            (CAPSULE_DIR.to_string(), capsule_dir.clone()),
+            (CAPSULE_INSTANCE.to_string(), instance.clone()),
+            (
+                PROCESS_CWD.to_string(),
+                process_cwd(name, proc)?.display().to_string(),
+            ),
+        ]);
+        merged.extend(global.clone());
+        merged.extend(proc.env.take().into_iter().flatten());
+        merged.extend(overlay.clone());
+        proc.env = Some(resolve_env(&merged, host)?);
+
+        if let Some(global) = &passthrough {
+            let own = proc.env_passthrough.take().unwrap_or_default();
+            proc.env_passthrough = Some(global.iter().cloned().chain(own).collect());
+        }
+    }
+    Ok(())
+}
+
+/// Sets the env of `cmd` to the allowed host vars and the resolved process vars
+pub fn apply(cmd: &mut Command, proc: &Process) {
+    if let Some(patterns) = &proc.env_passthrough {
+        cmd.env_clear();
+        cmd.envs(env::vars_os().filter(|(key, _)| {
+            key.to_str()
+                .is_some_and(|key| passthrough_allows(patterns, key))
+        }));
+    }
+    cmd.env_remove(PASSWORD_ENV);
+    if let Some(env) = &proc.env {
+        cmd.envs(env);
+    }
+}
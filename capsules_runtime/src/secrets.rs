@@ -0,0 +1,145 @@
+//! Secrets of the processes, unsealed when the supervisor starts and only kept
+//! in memory. File secrets go to a tmpfs directory removed on tear-down, they
+//! are never written to the data directory.
+
+use capsules_lib::env::CAPSULE_SECRETS_DIR;
+use capsules_lib::keys::KEY_SIZE;
+use capsules_lib::secrets::Injection;
+use capsules_lib::{Capsule, Env, Error, SetError, safe_relative_path};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+
+#[derive(Default)]
+struct Secrets {
+    /// Process name -> env vars of its secrets, `CAPSULE_SECRETS_DIR` included
+    env: HashMap<String, Env>,
+    /// File secrets of every process, one directory per process
+    dir: Option<PathBuf>,
+}
+
+static SECRETS: OnceLock<Secrets> = OnceLock::new();
+
+/// Unseals the secrets of `capsule` with the payload key or their own password
+/// and writes the file ones
+pub fn init(
+    capsule: &Capsule,
+    payload_key: Option<&[u8; KEY_SIZE]>,
+    password: Option<&str>,
+) -> Result<(), Error> {
+    let mut secrets = Secrets::default();
+    if let Err(e) = unseal(capsule, payload_key, password, &mut secrets) {
+        if let Some(dir) = &secrets.dir {
+            fs::remove_dir_all(dir).ok();
+        }
+        return Err(e);
+    }
+    SECRETS.set(secrets).set_error(Error::InternalError)
+}
+
+fn unseal(
+    capsule: &Capsule,
+    payload_key: Option<&[u8; KEY_SIZE]>,
+    password: Option<&str>,
+    secrets: &mut Secrets,
+) -> Result<(), Error> {
+    let Some(sealed) = &capsule.sealed_secrets else {
+        return Ok(());
+    };
+    let values = sealed.unseal(&sealed.key.resolve(payload_key, password)?)?;
+    for (name, process) in capsule.processes.iter().flatten() {
+        let uses = process.use_secrets.as_deref().unwrap_or_default();
+        let mut env = Env::new();
+        for secret in sealed.for_process(&values, name, uses) {
+            if secret.injection == Injection::Env {
+                env.insert(secret.name, secret.value);
+                continue;
+            }
+            let root = match &secrets.dir {
+                Some(root) => root.clone(),
+                None => secrets.dir.insert(create_root()?).clone(),
+            };
+            let dir = root.join(safe_relative_path(name)?);
+            fs::create_dir_all(&dir)
+                .set_error(Error::CouldNotCreatePath(dir.display().to_string()))?;
+            write_private(&dir.join(&secret.name), secret.value.as_bytes())?;
+            env.insert(CAPSULE_SECRETS_DIR.to_string(), dir.display().to_string());
+        }
+        secrets.env.insert(name.clone(), env);
+    }
+    Ok(())
+}
+
+/// Only readable by the user running the supervisor, removed by `clear`
+fn create_root() -> Result<PathBuf, Error> {
+    let root = tmpfs()?.join(format!("capsule-secrets-{}", std::process::id()));
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder
+        .create(&root)
+        .set_error(Error::CouldNotCreatePath(root.display().to_string()))?;
+    Ok(root)
+}
+
+fn write_private(path: &Path, content: &[u8]) -> Result<(), Error> {
+    use std::io::Write;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o400);
+    }
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(content))
+        .set_error(Error::CouldNotWriteFile(path.display().to_string()))
+}
+
+/// `$XDG_RUNTIME_DIR` or `/dev/shm`, whichever is a tmpfs
+#[cfg(target_os = "linux")]
+fn tmpfs() -> Result<PathBuf, Error> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let is_tmpfs = |dir: &PathBuf| {
+        let Ok(path) = CString::new(dir.as_os_str().as_bytes()) else {
+            return false;
+        };
+        // SAFETY: statfs only writes to stat
+        let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+        (unsafe { libc::statfs(path.as_ptr(), &mut stat) }) == 0
+            && stat.f_type as u64 == libc::TMPFS_MAGIC as u64
+    };
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .into_iter()
+        .chain([PathBuf::from("/dev/shm")])
+        .find(is_tmpfs)
+        .ok_or(Error::NoTmpfs)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn tmpfs() -> Result<PathBuf, Error> {
+    Err(Error::NoTmpfs)
+}
+
+/// Sets the secret env vars of process `name` on `cmd`
+pub fn apply(cmd: &mut Command, name: &str) {
+    if let Some(env) = SECRETS.get().and_then(|s| s.env.get(name)) {
+        cmd.envs(env);
+    }
+}
+
+/// Removes the file secrets, the supervisor is going away
+pub fn clear() {
+    if let Some(dir) = SECRETS.get().and_then(|s| s.dir.as_ref()) {
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,295 @@
+use capsules_lib::{
+    DEFAULT_STOP_TIMEOUT_MS, Process, RunningProcess, Status, StopOutcome, StopSignal,
+};
+use std::collections::HashMap;
+use std::io;
+use std::process::Child;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+fn send_signal(child: &mut Child, signal: StopSignal) -> io::Result<()> {
+    let signal = match signal {
+        StopSignal::Sigterm => libc::SIGTERM,
+        StopSignal::Sigint => libc::SIGINT,
+        StopSignal::Sigquit => libc::SIGQUIT,
+        StopSignal::Sighup => libc::SIGHUP,
+        StopSignal::Sigusr1 => libc::SIGUSR1,
+        StopSignal::Sigusr2 => libc::SIGUSR2,
+        StopSignal::Sigkill => libc::SIGKILL,
+    };
+    // SAFETY: the pid belongs to a child we have not reaped yet
+    match unsafe { libc::kill(child.id() as libc::pid_t, signal) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(unix))]
+fn send_signal(_child: &mut Child, _signal: StopSignal) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Sends the stop signal and waits up to `timeout` before killing the child
+pub fn stop_child(child: &mut Child, signal: StopSignal, timeout: Duration) -> StopOutcome {
+    stop_children(vec![(child, signal, timeout)])[0]
+}
+
+/// Sends the stop signal to every child first, then waits for all of them at
+/// once, killing each one that is still running after its own timeout
+fn stop_children(mut children: Vec<(&mut Child, StopSignal, Duration)>) -> Vec<StopOutcome> {
+    fn kill(child: &mut Child) -> StopOutcome {
+        child.kill().ok();
+        child.wait().ok();
+        StopOutcome::Killed
+    }
+
+    let started = Instant::now();
+    let mut outcomes: Vec<Option<StopOutcome>> = children
+        .iter_mut()
+        .map(|(child, signal, _)| {
+            if !matches!(child.try_wait(), Ok(None)) {
+                return Some(StopOutcome::NotRunning);
+            }
+            if *signal != StopSignal::Sigkill && send_signal(child, *signal).is_ok() {
+                return None;
+            }
+            Some(kill(child))
+        })
+        .collect();
+    while outcomes.iter().any(Option::is_none) {
+        thread::sleep(Duration::from_millis(10));
+        for ((child, _, timeout), outcome) in children.iter_mut().zip(&mut outcomes) {
+            if outcome.is_some() {
+                continue;
+            }
+            if let Ok(Some(_)) = child.try_wait() {
+                *outcome = Some(StopOutcome::Graceful);
+            } else if started.elapsed() >= *timeout {
+                *outcome = Some(kill(child));
+            }
+        }
+    }
+    outcomes.into_iter().flatten().collect()
+}
+
+/// Stop signal and timeout of the process
+pub fn settings(config: &Process) -> (StopSignal, Duration) {
+    let signal = config.stop_signal.unwrap_or(StopSignal::Sigterm);
+    let timeout = Duration::from_millis(config.stop_timeout_ms.unwrap_or(DEFAULT_STOP_TIMEOUT_MS));
+    (signal, timeout)
+}
+
+/// Stops the process and its overlapping scheduled runs.
+/// Blocks the caller for at most `stop_timeout_ms`
+pub fn stop(proc: &mut RunningProcess) -> StopOutcome {
+    stop_all_of(vec![proc]).remove(0)
+}
+
+/// Stops the named processes in reverse dependency order: a level of the
+/// dependency graph is stopped together, and has exited or was killed before
+/// the processes it depends on are signalled. Outcomes are in the given order
+pub fn stop_all<'a>(
+    table: &mut HashMap<String, RunningProcess>,
+    names: impl Iterator<Item = &'a String>,
+) -> Vec<(String, StopOutcome)> {
+    let names: Vec<&String> = names.collect();
+    let mut stopped = vec![];
+    for level in stop_levels(table, &names) {
+        let mut procs: Vec<&mut RunningProcess> = table
+            .iter_mut()
+            .filter(|(name, _)| level.contains(name))
+            .map(|(_, proc)| proc)
+            .collect();
+        for proc in procs.iter_mut() {
+            proc.status = Status::Killed;
+        }
+        let level: Vec<String> = procs.iter().map(|proc| proc.name.clone()).collect();
+        stopped.extend(level.into_iter().zip(stop_all_of(procs)));
+    }
+    stopped.sort_by_key(|(name, _)| names.iter().position(|n| *n == name));
+    stopped
+}
+
+/// `names` grouped by their depth in the dependency graph, deepest first
+fn stop_levels(table: &HashMap<String, RunningProcess>, names: &[&String]) -> Vec<Vec<String>> {
+    fn depth(
+        table: &HashMap<String, RunningProcess>,
+        name: &str,
+        depths: &mut HashMap<String, usize>,
+    ) -> usize {
+        if let Some(depth) = depths.get(name) {
+            return *depth;
+        }
+        let deps = table
+            .get(name)
+            .and_then(|proc| proc.config.depends_on.as_ref());
+        // start_order already refused cycles
+        let own = deps
+            .iter()
+            .flat_map(|deps| deps.keys())
+            .map(|dep| depth(table, dep, depths) + 1)
+            .max()
+            .unwrap_or(0);
+        depths.insert(name.to_string(), own);
+        own
+    }
+
+    let mut depths = HashMap::new();
+    let mut levels: Vec<Vec<String>> = vec![];
+    for name in names.iter().filter(|name| table.contains_key(**name)) {
+        let depth = depth(table, name, &mut depths);
+        if levels.len() <= depth {
+            levels.resize(depth + 1, vec![]);
+        }
+        levels[depth].push(name.to_string());
+    }
+    levels.retain(|level| !level.is_empty());
+    levels.reverse();
+    levels
+}
+
+/// Outcome of the main child of each process, overlapping runs are stopped
+/// along with it
+fn stop_all_of(procs: Vec<&mut RunningProcess>) -> Vec<StopOutcome> {
+    let mut overlapping: Vec<(Child, StopSignal, Duration)> = vec![];
+    let mut main = vec![];
+    let mut idle = vec![];
+    for (i, proc) in procs.into_iter().enumerate() {
+        let (signal, timeout) = settings(&proc.config);
+        overlapping.extend(
+            proc.overlapping
+                .drain(..)
+                .map(|(child, _)| (child, signal, timeout)),
+        );
+        match &mut proc.child {
+            Some(child) => main.push((child, signal, timeout)),
+            None => idle.push(i),
+        }
+    }
+    let mut children: Vec<(&mut Child, StopSignal, Duration)> = main;
+    let count = children.len();
+    children.extend(
+        overlapping
+            .iter_mut()
+            .map(|(child, signal, timeout)| (child, *signal, *timeout)),
+    );
+    let mut outcomes = stop_children(children);
+    outcomes.truncate(count);
+    for i in idle {
+        outcomes.insert(i, StopOutcome::NotRunning);
+    }
+    outcomes
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{stop_all, stop_levels};
+    use capsules_lib::scratch::Scratch;
+    use capsules_lib::{Process, RunningProcess, StopOutcome, start_order};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::process::Command;
+    use std::thread;
+    use std::time::Duration;
+
+    fn config(script: &str, deps: &[&str]) -> Process {
+        let deps: HashMap<&str, &str> = deps.iter().map(|dep| (*dep, "started")).collect();
+        serde_json::from_value(serde_json::json!({
+            "cmd": "sh",
+            "args": ["-c", script],
+            "depends_on": deps,
+            "stop_timeout_ms": 5000,
+        }))
+        .unwrap()
+    }
+
+    /// Appends its name to `log` once stopped, `delay` seconds after the signal
+    fn process(name: &str, log: &str, delay: &str, deps: &[&str]) -> RunningProcess {
+        let script = format!(
+            "trap 'sleep {delay}; echo {name} >> {log}; exit 0' TERM; while :; do sleep 0.01; done"
+        );
+        let mut proc = RunningProcess::new(name, config(&script, deps));
+        proc.child = Some(
+            Command::new(&proc.config.cmd)
+                .args(proc.config.args.iter().flatten())
+                .spawn()
+                .unwrap(),
+        );
+        proc
+    }
+
+    #[test]
+    fn dependents_exit_before_their_dependencies_are_signalled() {
+        let dir = Scratch::new("stop-order");
+        let log = dir.join("stopped.log");
+        let path = log.to_str().unwrap();
+        let mut table: HashMap<String, RunningProcess> = [
+            process("db", path, "0", &[]),
+            // still running for a while after its stop signal
+            process("api", path, "0.3", &["db"]),
+        ]
+        .into_iter()
+        .map(|proc| (proc.name.clone(), proc))
+        .collect();
+        // let the traps be installed
+        thread::sleep(Duration::from_millis(200));
+
+        let names = ["db".to_string(), "api".to_string()];
+        let stopped = stop_all(&mut table, names.iter());
+        assert_eq!(
+            stopped,
+            [
+                ("db".to_string(), StopOutcome::Graceful),
+                ("api".to_string(), StopOutcome::Graceful),
+            ]
+        );
+        assert_eq!(fs::read_to_string(&log).unwrap(), "api\ndb\n");
+    }
+
+    #[test]
+    fn processes_stop_in_reverse_start_order() {
+        let processes: HashMap<String, Process> = [
+            ("db", config("", &[])),
+            ("cache", config("", &[])),
+            ("api", config("", &["db", "cache"])),
+            ("worker", config("", &["api"])),
+            ("web", config("", &["api"])),
+            ("metrics", config("", &[])),
+        ]
+        .into_iter()
+        .map(|(name, config)| (name.to_string(), config))
+        .collect();
+        let order = start_order(&processes).unwrap();
+        let table: HashMap<String, RunningProcess> = processes
+            .into_iter()
+            .map(|(name, config)| (name.clone(), RunningProcess::new(&name, config)))
+            .collect();
+
+        let names: Vec<&String> = order.iter().rev().collect();
+        let levels = stop_levels(&table, &names);
+        assert_eq!(
+            levels,
+            [
+                vec!["worker", "web"],
+                vec!["api"],
+                vec!["metrics", "db", "cache"],
+            ]
+        );
+        // every process stops before the ones it was started after
+        let stopped: Vec<&String> = levels.iter().flatten().collect();
+        for (i, name) in stopped.iter().enumerate() {
+            for dep in table[*name].config.depends_on.iter().flat_map(|d| d.keys()) {
+                assert!(
+                    stopped[i..].contains(&dep),
+                    "{} stopped before {}",
+                    dep,
+                    name
+                );
+            }
+        }
+        // a single process is a level of its own
+        let api = "api".to_string();
+        assert_eq!(stop_levels(&table, &[&api]), [vec!["api"]]);
+    }
+}
@@ -0,0 +1,111 @@
+//! Keys trusted to sign capsules: baked in when the runtime is built, from the
+//! `CAPSULES_TRUSTED_KEYS` env var, and read from `--trusted-keys`. Once there
+//! is one, capsules that are unsigned or signed by another key are refused, or
+//! only warned about with `--signature-policy warn`. Without one, any capsule
+//! is run after a warning. Damaged capsules are always refused.
+
+use capsules_lib::Error;
+use capsules_lib::keys::KEY_SIZE;
+use capsules_lib::payload::{Payload, hex};
+use capsules_lib::signing::{Verification, parse_trusted_keys, read_trusted_keys};
+use clap::{Args, ValueEnum};
+use std::io::{Read, Seek};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::OnceLock;
+
+const BAKED_KEYS: Option<&str> = option_env!("CAPSULES_TRUSTED_KEYS");
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum Policy {
+    /// Do not run capsules that are not signed by a trusted key
+    #[default]
+    Refuse,
+    /// Run them after printing a warning
+    Warn,
+}
+
+#[derive(Args, Debug)]
+pub struct TrustArgs {
+    /// File of Ed25519 public keys trusted to sign the capsule, in hex, on top
+    /// of the ones built into the runtime
+    #[arg(long, global = true, value_name = "FILE")]
+    trusted_keys: Option<PathBuf>,
+
+    /// What to do with a capsule not signed by a trusted key, when there is one
+    #[arg(long, global = true, value_enum, default_value_t = Policy::Refuse)]
+    signature_policy: Policy,
+}
+
+struct Trust {
+    keys: Vec<[u8; KEY_SIZE]>,
+    policy: Policy,
+    file: Option<PathBuf>,
+}
+
+static TRUST: OnceLock<Trust> = OnceLock::new();
+
+/// Hashing the executable is slow, it is done once per command
+static VERIFICATION: OnceLock<Verification> = OnceLock::new();
+
+pub fn init(args: &TrustArgs) -> Result<(), Error> {
+    let mut keys = parse_trusted_keys(BAKED_KEYS.unwrap_or_default())?;
+    if let Some(path) = &args.trusted_keys {
+        keys.extend(read_trusted_keys(path)?);
+    }
+    let trust = Trust {
+        keys,
+        policy: args.signature_policy,
+        file: args.trusted_keys.clone(),
+    };
+    TRUST.set(trust).map_err(|_| Error::InternalError)
+}
+
+fn get() -> Result<&'static Trust, Error> {
+    TRUST.get().ok_or(Error::InternalError)
+}
+
+pub fn keys() -> Result<&'static [[u8; KEY_SIZE]], Error> {
+    Ok(&get()?.keys)
+}
+
+/// Digest and signature of the capsule, checked on the first call only
+pub fn verification<R: Read + Seek>(
+    payload: &mut Payload<R>,
+) -> Result<&'static Verification, Error> {
+    let trust = get()?;
+    let verification = VERIFICATION.get_or_init(|| Verification::new(payload, &trust.keys));
+    // the executable was replaced since
+    if verification.digest != hex(payload.digest()) {
+        return Err(Error::IntegrityCheckFailed);
+    }
+    Ok(verification)
+}
+
+/// Checks the digest of `payload`, then its signature against the trusted keys
+pub fn check<R: Read + Seek>(payload: &mut Payload<R>) -> Result<(), Error> {
+    let trust = get()?;
+    match verification(payload)?.result(&trust.keys) {
+        Ok(()) if trust.keys.is_empty() => {
+            eprintln!("Warning: no trusted keys, the signature of the capsule is not checked");
+            Ok(())
+        }
+        Err(Error::SignatureCheckFailed(reason)) if trust.policy == Policy::Warn => {
+            eprintln!("Warning: {}", reason);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Hands the trust options to the supervisor, it checks the capsule again
+pub fn forward(cmd: &mut Command) -> Result<(), Error> {
+    let trust = get()?;
+    if let Some(file) = &trust.file {
+        cmd.arg("--trusted-keys").arg(file);
+    }
+    if trust.policy == Policy::Warn {
+        cmd.arg("--signature-policy").arg("warn");
+    }
+    Ok(())
+}